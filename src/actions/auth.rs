@@ -0,0 +1,176 @@
+use std::env;
+use std::sync::Arc;
+
+use crate::config::AuthMethod;
+use crate::prompt;
+use russh::client::{self, KeyboardInteractiveAuthResponse};
+use russh_keys::agent::client::AgentClient;
+use russh_keys::load_secret_key;
+
+use super::commands::SimpleHandler;
+use super::upload::expand_user_path;
+
+fn describe(method: &AuthMethod) -> String {
+    match method {
+        AuthMethod::Agent => "agent".to_string(),
+        AuthMethod::Key { path, .. } => format!("key '{}'", path),
+        AuthMethod::Password { .. } => "password".to_string(),
+        AuthMethod::KeyboardInteractive => "keyboard-interactive".to_string(),
+    }
+}
+
+fn ask(question: &str, hidden: bool, interactive: bool) -> Result<String, String> {
+    if !interactive {
+        return Err("no terminal to prompt for it".to_string());
+    }
+    tokio::task::block_in_place(|| prompt::read_line(question, hidden))
+        .ok_or_else(|| "cancelled".to_string())
+}
+
+fn from_env_or_ask(
+    var: &Option<String>,
+    question: &str,
+    interactive: bool,
+) -> Result<String, String> {
+    match var {
+        Some(name) => env::var(name).map_err(|_| format!("${} is not set", name)),
+        None => ask(question, true, interactive),
+    }
+}
+
+async fn with_agent(
+    session: &mut client::Handle<SimpleHandler>,
+    user: &str,
+) -> Result<bool, String> {
+    let mut agent = AgentClient::connect_env()
+        .await
+        .map_err(|e| format!("unable to reach the agent through SSH_AUTH_SOCK: {}", e))?;
+    let identities = agent
+        .request_identities()
+        .await
+        .map_err(|e| format!("unable to list agent identities: {}", e))?;
+
+    for key in identities {
+        let (returned, result) = session.authenticate_future(user, key, agent).await;
+        agent = returned;
+        if result.map_err(|e| e.to_string())? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+async fn with_key(
+    session: &mut client::Handle<SimpleHandler>,
+    user: &str,
+    path: &str,
+    passphrase_env: &Option<String>,
+    interactive: bool,
+) -> Result<bool, String> {
+    let path = expand_user_path(path);
+    let key = match load_secret_key(&path, None) {
+        Ok(key) => key,
+        Err(russh_keys::Error::KeyIsEncrypted) => {
+            let passphrase = from_env_or_ask(
+                passphrase_env,
+                &format!("Passphrase for '{}':", path),
+                interactive,
+            )?;
+            load_secret_key(&path, Some(&passphrase)).map_err(|e| e.to_string())?
+        }
+        Err(e) => return Err(e.to_string()),
+    };
+    session
+        .authenticate_publickey(user, Arc::new(key))
+        .await
+        .map_err(|e| e.to_string())
+}
+
+async fn with_password(
+    session: &mut client::Handle<SimpleHandler>,
+    user: &str,
+    password_env: &Option<String>,
+    interactive: bool,
+) -> Result<bool, String> {
+    let password = from_env_or_ask(
+        password_env,
+        &format!("Password for '{}':", user),
+        interactive,
+    )?;
+    session
+        .authenticate_password(user, password)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+async fn with_keyboard_interactive(
+    session: &mut client::Handle<SimpleHandler>,
+    user: &str,
+    interactive: bool,
+) -> Result<bool, String> {
+    let mut response = session
+        .authenticate_keyboard_interactive_start(user, None)
+        .await
+        .map_err(|e| e.to_string())?;
+    loop {
+        match response {
+            KeyboardInteractiveAuthResponse::Success => return Ok(true),
+            KeyboardInteractiveAuthResponse::Failure => return Ok(false),
+            KeyboardInteractiveAuthResponse::InfoRequest {
+                name,
+                instructions,
+                prompts,
+            } => {
+                for line in [name, instructions] {
+                    if !line.is_empty() {
+                        println!("{}", line);
+                    }
+                }
+                let mut answers = Vec::new();
+                for request in prompts {
+                    answers.push(ask(request.prompt.trim_end(), !request.echo, interactive)?);
+                }
+                response = session
+                    .authenticate_keyboard_interactive_respond(answers)
+                    .await
+                    .map_err(|e| e.to_string())?;
+            }
+        }
+    }
+}
+
+// Tries each method in order until the server accepts one, methods needing
+// a prompt fail when not interactive
+pub async fn authenticate(
+    session: &mut client::Handle<SimpleHandler>,
+    user: &str,
+    methods: &[AuthMethod],
+    interactive: bool,
+) -> Result<(), String> {
+    let mut failures = Vec::new();
+    for method in methods {
+        let result = match method {
+            AuthMethod::Agent => with_agent(session, user).await,
+            AuthMethod::Key {
+                path,
+                passphrase_env,
+            } => with_key(session, user, path, passphrase_env, interactive).await,
+            AuthMethod::Password { password_env } => {
+                with_password(session, user, password_env, interactive).await
+            }
+            AuthMethod::KeyboardInteractive => {
+                with_keyboard_interactive(session, user, interactive).await
+            }
+        };
+        match result {
+            Ok(true) => return Ok(()),
+            Ok(false) => failures.push(format!("{} (rejected)", describe(method))),
+            Err(reason) => failures.push(format!("{} ({})", describe(method), reason)),
+        }
+    }
+    Err(format!(
+        "Unable to authenticate as '{}', tried: {}",
+        user,
+        failures.join(", ")
+    ))
+}
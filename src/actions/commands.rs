@@ -1,18 +1,26 @@
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
-use crate::config::Config;
+use crate::config::Server;
 use crate::log;
+use crate::logger::{RemoteExit, RemoteWatch};
 use crate::Logger;
 use async_trait::async_trait;
 use colored::Colorize;
 use russh::client;
-use russh::Channel;
+use russh::{Channel, Disconnect};
 use russh_keys::key::PublicKey;
-use russh_keys::load_secret_key;
 
-use super::upload::expand_user_path;
+use super::auth::authenticate;
+use super::known_hosts;
 
-pub struct SimpleHandler;
+pub struct SimpleHandler {
+    host: String,
+    port: u16,
+    pinned: Vec<String>,
+    interactive: bool,
+    // why the server key was refused, read back once connect fails
+    rejection: Arc<Mutex<Option<String>>>,
+}
 
 #[async_trait]
 impl client::Handler for SimpleHandler {
@@ -20,18 +28,61 @@ impl client::Handler for SimpleHandler {
 
     async fn check_server_key(
         self,
-        _server_public_key: &PublicKey,
+        server_public_key: &PublicKey,
     ) -> Result<(Self, bool), Self::Error> {
-        Ok((self, true))
+        let accepted = match known_hosts::verify(
+            &self.host,
+            self.port,
+            server_public_key,
+            &self.pinned,
+            self.interactive,
+        ) {
+            Ok(()) => true,
+            Err(reason) => {
+                *self.rejection.lock().unwrap() = Some(reason);
+                false
+            }
+        };
+        Ok((self, accepted))
+    }
+}
+
+// Joins the commands into the single line executed on the server, with
+// stderr redirected to stdout to keep their relative order when merged
+pub fn forge_command(commands: &[String], merge_stderr: bool) -> String {
+    let joined = commands.join(" && ");
+    if merge_stderr {
+        format!("{{ {}; }} 2>&1", joined)
+    } else {
+        joined
     }
 }
 
+// Runs the command in its own session, immune to the hangup of the SSH
+// session, with its output relayed through FIFOs whose folder is removed as
+// soon as they are opened. Once the deployment detaches and the channel is
+// closed, the relays discard the output instead of keeping it on the server.
+fn keep_running_command(command: &str) -> String {
+    let quoted = format!("'{}'", command.replace('\'', "'\\''"));
+    format!(
+        "dir=$(mktemp -d) && mkfifo \"$dir/out\" \"$dir/err\" && {{ \
+         {{ trap '' HUP PIPE; cat || cat >/dev/null; }} <\"$dir/out\" & \
+         {{ trap '' HUP PIPE; cat >&2 || cat >/dev/null; }} <\"$dir/err\" & \
+         setsid nohup sh -c 'rm -rf \"$1\"; exec sh -c \"$2\"' sh \"$dir\" {} \
+         >\"$dir/out\" 2>\"$dir/err\" </dev/null & pid=$!; \
+         wait $pid; status=$?; wait; exit $status; }}",
+        quoted
+    )
+}
+
 pub async fn send_command(
     logger: &mut Logger,
-    session: &client::Handle<SimpleHandler>,
+    channel: Channel<client::Msg>,
     commands: &Vec<String>,
-) {
-    let forged_command = commands.join(" && ");
+    merge_stderr: bool,
+    watch: &RemoteWatch,
+) -> RemoteExit {
+    let forged_command = forge_command(commands, merge_stderr);
     log!(
         logger,
         "{}{}{}",
@@ -40,26 +91,168 @@ pub async fn send_command(
         "\'".bright_black()
     );
 
-    let channel: Channel<client::Msg> = session.channel_open_session().await.unwrap();
+    let executed = if watch.keep_running {
+        keep_running_command(&forged_command)
+    } else {
+        forged_command
+    };
     channel
-        .exec(true, forged_command)
+        .exec(true, executed)
         .await
         .expect("Unable to send command");
 
-    logger.start_remote_logging(channel).await;
+    let exit = logger.start_remote_logging(channel, watch).await;
+    match &exit {
+        RemoteExit::Status(code) => {
+            let code_str = code.to_string();
+            log!(
+                logger,
+                "{} {}",
+                "Exit status:".bright_black(),
+                if *code == 0 {
+                    code_str.green()
+                } else {
+                    code_str.bright_red()
+                }
+            );
+        }
+        RemoteExit::Signal(signal) => {
+            log!(
+                logger,
+                "{} {}",
+                "Exit signal:".bright_black(),
+                signal.bright_red()
+            );
+        }
+        _ => {}
+    }
+    exit
+}
+
+// Final session along with the sessions of the jump hosts it goes through,
+// which must stay open as long as it is used
+pub struct SshSession {
+    pub handle: client::Handle<SimpleHandler>,
+    jumps: Vec<client::Handle<SimpleHandler>>,
+}
+
+impl SshSession {
+    pub async fn disconnect(self) -> Result<(), russh::Error> {
+        self.handle
+            .disconnect(Disconnect::ByApplication, "", "English")
+            .await?;
+        for jump in self.jumps.into_iter().rev() {
+            jump.disconnect(Disconnect::ByApplication, "", "English")
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+pub fn describe_hop(server: &Server) -> String {
+    if server.host.contains(':') {
+        format!("{}@[{}]:{}", server.user(), server.host, server.port())
+    } else {
+        format!("{}@{}:{}", server.user(), server.host, server.port())
+    }
 }
 
-pub async fn create_ssh_session(conf: &Config) -> client::Handle<SimpleHandler> {
-    let key = load_secret_key(expand_user_path(&conf.server.ssh_key), None).unwrap();
+// Connects to a single hop, directly or through a channel of the previous one
+async fn connect_hop(
+    logger: &mut Logger,
+    server: &Server,
+    previous: Option<&client::Handle<SimpleHandler>>,
+) -> Option<client::Handle<SimpleHandler>> {
     let config: Arc<_> = Arc::new(client::Config::default());
-    let sh = SimpleHandler;
+    let rejection = Arc::new(Mutex::new(None));
+    let sh = SimpleHandler {
+        host: server.host.to_owned(),
+        port: server.port(),
+        pinned: server
+            .host_key_fingerprint
+            .as_ref()
+            .map(|fingerprints| fingerprints.to_vec())
+            .unwrap_or_default(),
+        interactive: !logger.is_plain(),
+        rejection: rejection.clone(),
+    };
 
-    let mut session = client::connect(config, (conf.server.host.to_owned(), conf.server.port), sh)
-        .await
-        .unwrap();
-    session
-        .authenticate_publickey(conf.server.user.to_owned(), Arc::new(key))
-        .await
-        .expect("Unable to connect via SSH");
-    session
+    let connected = match previous {
+        None => client::connect(config, (server.host.to_owned(), server.port()), sh).await,
+        Some(previous) => {
+            match previous
+                .channel_open_direct_tcpip(
+                    server.host.to_owned(),
+                    server.port().into(),
+                    "127.0.0.1",
+                    0,
+                )
+                .await
+            {
+                Ok(channel) => client::connect_stream(config, channel.into_stream(), sh).await,
+                Err(err) => Err(err),
+            }
+        }
+    };
+    let mut session = match connected {
+        Ok(session) => session,
+        Err(err) => {
+            let reason = rejection.lock().unwrap().take();
+            log!(
+                logger,
+                "{} {}",
+                "Error:".bright_red(),
+                reason.unwrap_or_else(|| format!(
+                    "Unable to connect to '{}': {}",
+                    describe_hop(server),
+                    err
+                ))
+            );
+            return None;
+        }
+    };
+    if let Err(reason) = authenticate(
+        &mut session,
+        server.user(),
+        &server.auth_methods(),
+        !logger.is_plain(),
+    )
+    .await
+    {
+        log!(logger, "{} {}", "Error:".bright_red(), reason);
+        return None;
+    }
+    Some(session)
+}
+
+// Opens a session to the server, hopping through its jump hosts if any
+pub async fn create_ssh_session(logger: &mut Logger, server: &Server) -> Option<SshSession> {
+    let hops: Vec<&Server> = server
+        .jump
+        .iter()
+        .flatten()
+        .chain(std::iter::once(server))
+        .collect();
+    if hops.len() > 1 {
+        log!(
+            logger,
+            "{} {}",
+            "Route:".bright_black(),
+            hops.iter()
+                .map(|hop| describe_hop(hop))
+                .collect::<Vec<String>>()
+                .join(" -> ")
+        );
+    }
+
+    let mut sessions: Vec<client::Handle<SimpleHandler>> = Vec::new();
+    for hop in hops {
+        let session = connect_hop(&mut *logger, hop, sessions.last()).await?;
+        sessions.push(session);
+    }
+    let handle = sessions.pop()?;
+    Some(SshSession {
+        handle,
+        jumps: sessions,
+    })
 }
@@ -0,0 +1,108 @@
+use crate::config::Server;
+use crate::log;
+use crate::Logger;
+use colored::Colorize;
+use russh::{client, Channel};
+use russh_sftp::client::SftpSession;
+
+use super::commands::{create_ssh_session, SimpleHandler, SshSession};
+
+// One SSH session per server, opened on first use and shared by every action.
+// Each action gets its own channel and the session is reopened if it dropped.
+pub struct Connection {
+    server: Server,
+    session: Option<SshSession>,
+}
+
+impl Connection {
+    pub fn new(server: &Server) -> Connection {
+        Connection {
+            server: server.clone(),
+            session: None,
+        }
+    }
+
+    async fn session(&mut self, logger: &mut Logger) -> Option<&client::Handle<SimpleHandler>> {
+        let alive = match &self.session {
+            Some(session) => !session.handle.is_closed(),
+            None => false,
+        };
+        if !alive {
+            if self.session.is_some() {
+                log!(
+                    logger,
+                    "{} connection to '{}' lost, reconnecting",
+                    "Warning:".yellow(),
+                    self.server.host
+                );
+            }
+            self.session = create_ssh_session(logger, &self.server).await;
+        }
+        self.session.as_ref().map(|session| &session.handle)
+    }
+
+    pub async fn open_channel(&mut self, logger: &mut Logger) -> Option<Channel<client::Msg>> {
+        for attempt in 0..2 {
+            let session = self.session(&mut *logger).await?;
+            match session.channel_open_session().await {
+                Ok(channel) => return Some(channel),
+                Err(err) if attempt == 0 => {
+                    log!(
+                        logger,
+                        "{} unable to open a channel ({}), reconnecting",
+                        "Warning:".yellow(),
+                        err
+                    );
+                    self.session = None;
+                }
+                Err(err) => {
+                    log!(
+                        logger,
+                        "{} Unable to open a channel: {}",
+                        "Error:".bright_red(),
+                        err
+                    );
+                }
+            }
+        }
+        None
+    }
+
+    pub async fn open_sftp(&mut self, logger: &mut Logger) -> Option<SftpSession> {
+        let channel = self.open_channel(&mut *logger).await?;
+        let sftp = match channel.request_subsystem(true, "sftp").await {
+            Ok(()) => SftpSession::new(channel.into_stream())
+                .await
+                .map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        match sftp {
+            Ok(sftp) => Some(sftp),
+            Err(err) => {
+                log!(
+                    logger,
+                    "{} Unable to connect in SFTP: {}",
+                    "Error:".bright_red(),
+                    err
+                );
+                None
+            }
+        }
+    }
+
+    // The deployment already happened, a session that cannot be closed
+    // cleanly (such as after a reboot) is only worth a warning
+    pub async fn close(self, logger: &mut Logger) {
+        if let Some(session) = self.session {
+            if let Err(err) = session.disconnect().await {
+                log!(
+                    logger,
+                    "{} unable to close the connection to '{}': {}",
+                    "Warning:".yellow(),
+                    self.server.host,
+                    err
+                );
+            }
+        }
+    }
+}
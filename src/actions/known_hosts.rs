@@ -0,0 +1,113 @@
+use crate::prompt;
+use colored::Colorize;
+use russh_keys::key::PublicKey;
+use russh_keys::{known_host_keys_path, learn_known_hosts_path};
+
+use super::upload::expand_user_path;
+
+const KNOWN_HOSTS: &str = "~/.ssh/known_hosts";
+
+fn display_host(host: &str, port: u16) -> String {
+    if port == 22 {
+        host.to_string()
+    } else {
+        format!("[{}]:{}", host, port)
+    }
+}
+
+pub fn fingerprint(key: &PublicKey) -> String {
+    format!("SHA256:{}", key.fingerprint())
+}
+
+// "SHA256:abc=" and "abc" designate the same fingerprint
+fn normalize(fingerprint: &str) -> &str {
+    fingerprint
+        .trim()
+        .trim_start_matches("SHA256:")
+        .trim_end_matches('=')
+}
+
+fn verify_pinned(host: &str, port: u16, key: &PublicKey, pinned: &[String]) -> Result<(), String> {
+    let received = fingerprint(key);
+    if pinned
+        .iter()
+        .any(|expected| normalize(expected) == normalize(&received))
+    {
+        return Ok(());
+    }
+    Err(format!(
+        "Host key verification failed for {}, the key does not match host_key_fingerprint!\n  expected: {}\n  received: {}",
+        display_host(host, port),
+        pinned.join(", "),
+        received
+    ))
+}
+
+// Checks the key against the pinned fingerprints if any, otherwise against
+// ~/.ssh/known_hosts (hashed entries included) and offers to trust hosts
+// seen for the first time when running interactively
+pub fn verify(
+    host: &str,
+    port: u16,
+    key: &PublicKey,
+    pinned: &[String],
+    interactive: bool,
+) -> Result<(), String> {
+    if !pinned.is_empty() {
+        return verify_pinned(host, port, key, pinned);
+    }
+
+    let path = expand_user_path(KNOWN_HOSTS);
+    let known = known_host_keys_path(host, port, &path)
+        .map_err(|e| format!("Unable to read {}: {}", path, e))?;
+
+    let same_type: Vec<&(usize, PublicKey)> = known
+        .iter()
+        .filter(|(_, recorded)| recorded.name() == key.name())
+        .collect();
+    if same_type.iter().any(|(_, recorded)| recorded == key) {
+        return Ok(());
+    }
+
+    if !same_type.is_empty() {
+        let expected = same_type
+            .iter()
+            .map(|(line, recorded)| format!("{} ({}:{})", fingerprint(recorded), KNOWN_HOSTS, line))
+            .collect::<Vec<String>>()
+            .join(", ");
+        return Err(format!(
+            "Host key verification failed for {}, the key has changed!\n  expected: {}\n  received: {}",
+            display_host(host, port),
+            expected,
+            fingerprint(key)
+        ));
+    }
+
+    if !interactive {
+        return Err(format!(
+            "'{}' is not a known host ({} key fingerprint is {}), add it to {} or pin it with host_key_fingerprint",
+            display_host(host, port),
+            key.name(),
+            fingerprint(key),
+            KNOWN_HOSTS
+        ));
+    }
+
+    println!(
+        "{} '{}' is not a known host, {} key fingerprint is {}",
+        "Warning:".yellow(),
+        display_host(host, port),
+        key.name(),
+        fingerprint(key).cyan()
+    );
+    let trusted = tokio::task::block_in_place(|| prompt::confirm("Trust this host and continue?"));
+    if !trusted {
+        return Err(format!(
+            "Host key for {} was not trusted",
+            display_host(host, port)
+        ));
+    }
+
+    learn_known_hosts_path(host, port, key, &path)
+        .map_err(|e| format!("Unable to add host to {}: {}", path, e))
+}
@@ -1,3 +1,9 @@
+mod auth;
 pub mod commands;
+mod connection;
+mod known_hosts;
+pub mod plan;
 pub mod runner;
 mod upload;
+
+pub use upload::{expand_user_path, render_templates};
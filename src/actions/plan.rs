@@ -0,0 +1,198 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use crate::actions::commands::{describe_hop, forge_command};
+use crate::actions::connection::Connection;
+use crate::actions::upload::{expand_server_path, expand_user_path, source_walker};
+use crate::config::{Action, Config, Server};
+use crate::log;
+use crate::logger::Logger;
+use colored::Colorize;
+use russh_sftp::client::SftpSession;
+
+fn format_size(bytes: u64) -> String {
+    match bytes {
+        b if b < 1024 => format!("{} B", b),
+        b if b < 1024 * 1024 => format!("{:.1} kB", b as f64 / 1024.0),
+        b if b < 1024 * 1024 * 1024 => format!("{:.1} MB", b as f64 / (1024.0 * 1024.0)),
+        b => format!("{:.1} GB", b as f64 / (1024.0 * 1024.0 * 1024.0)),
+    }
+}
+
+fn describe_server(server: &Server) -> String {
+    match &server.jump {
+        Some(jump) => format!(
+            "{} through {}",
+            describe_hop(server),
+            jump.iter()
+                .map(describe_hop)
+                .collect::<Vec<String>>()
+                .join(" -> ")
+        ),
+        None => describe_hop(server),
+    }
+}
+
+// Files the upload would send, relative to the source folder, with their size
+fn local_files(source_folder: &str) -> Vec<(PathBuf, u64)> {
+    let mut files = Vec::new();
+    for entry in source_walker(source_folder).build().flatten() {
+        let path = entry.path();
+        if !path.is_file() {
+            continue;
+        }
+        let size = path.metadata().map(|metadata| metadata.len()).unwrap_or(0);
+        if let Ok(relative_path) = path.strip_prefix(source_folder) {
+            files.push((relative_path.to_path_buf(), size));
+        }
+    }
+    files.sort();
+    files
+}
+
+async fn plan_upload(
+    logger: &mut Logger,
+    server: &Server,
+    sftp: Option<&mut SftpSession>,
+    source_folder: &str,
+    target_folder: &str,
+) {
+    let source_folder = expand_user_path(source_folder);
+    let target_folder = expand_server_path(target_folder, server.user());
+    let files = local_files(&source_folder);
+    let total: u64 = files.iter().map(|(_, size)| size).sum();
+    log!(
+        logger,
+        "     {} files, {} from '{}' to '{}'",
+        files.len(),
+        format_size(total),
+        source_folder,
+        target_folder
+    );
+
+    let mut sftp = sftp;
+    for (relative_path, size) in files {
+        let status = match sftp.as_deref_mut() {
+            Some(sftp) => {
+                let target_path = Path::new(&target_folder).join(&relative_path);
+                match sftp.try_exists(target_path.to_string_lossy()).await {
+                    Ok(true) => format!(" {}", "overwrite".yellow()),
+                    Ok(false) => format!(" {}", "create".green()),
+                    Err(_) => format!(" {}", "unknown".bright_black()),
+                }
+            }
+            None => String::new(),
+        };
+        log!(
+            logger,
+            "     {} '{}' ({}){}",
+            "▏".bright_cyan(),
+            relative_path.display().to_string().bright_black(),
+            format_size(size),
+            status
+        );
+    }
+}
+
+fn describe_action(action: &Action) -> String {
+    match action {
+        Action::Commands {
+            commands,
+            merge_stderr,
+            ..
+        } => forge_command(commands, *merge_stderr),
+        Action::Upload { .. } => "upload".to_string(),
+    }
+}
+
+// Prints what the deployment would do on each host without running anything.
+// With `remote`, connects to each host to tell which uploaded files would be
+// created or overwritten, without modifying anything.
+pub async fn print_plan(
+    logger: &mut Logger,
+    config: &Config,
+    skip: &HashSet<String>,
+    remote: bool,
+) {
+    if let Some(canary) = config.canary() {
+        log!(
+            logger,
+            "{} '{}' is deployed first",
+            "Canary:".bright_black(),
+            canary.host.unwrap_or_default()
+        );
+    }
+    if let Some(serial) = &config.serial {
+        log!(
+            logger,
+            "{} batches of {} servers",
+            "Serial:".bright_black(),
+            serial.batch_size(config.servers.len()).unwrap_or(1)
+        );
+    }
+
+    for (host_name, server) in &config.servers {
+        log!(
+            logger,
+            "{} {} ({})",
+            "Host:".bright_black(),
+            host_name.bright_green(),
+            describe_server(server)
+        );
+        let mut connection = remote.then(|| Connection::new(server));
+        let mut sftp = None;
+        if let Some(connection) = connection.as_mut() {
+            sftp = connection.open_sftp(&mut *logger).await;
+        }
+
+        let mut index = 0;
+        for action in &config.actions {
+            // targets were checked when loading the config
+            let hosts = config.action_hosts(action).unwrap_or_default();
+            if !hosts.iter().any(|name| name == host_name) {
+                continue;
+            }
+            index += 1;
+            if skip.contains(action.name()) {
+                log!(
+                    logger,
+                    "  {}. {} {}",
+                    index,
+                    action.name().bright_black(),
+                    "(skipped)".yellow()
+                );
+                continue;
+            }
+            log!(
+                logger,
+                "  {}. {}: {}",
+                index,
+                action.name(),
+                describe_action(action).cyan()
+            );
+            if let Action::Upload {
+                source_folder,
+                target_folder,
+                ..
+            } = action
+            {
+                plan_upload(
+                    &mut *logger,
+                    server,
+                    sftp.as_mut(),
+                    source_folder,
+                    target_folder,
+                )
+                .await;
+            }
+        }
+
+        if let Some(sftp) = sftp {
+            let _ = sftp.close().await;
+        }
+        if let Some(connection) = connection {
+            connection.close(&mut *logger).await;
+        }
+    }
+    log!(logger, "{} nothing was executed", "Plan:".bright_black());
+}
@@ -1,56 +1,472 @@
+use crate::actions::connection::Connection;
 use crate::actions::upload::upload;
-use crate::config::Action;
-use crate::{
-    actions::commands::{create_ssh_session, send_command},
-    config::Config,
-    logger::Logger,
-};
+use crate::config::{parse_duration, Action, Canary, Serial, Server};
+use crate::log;
+use crate::logger::{RemoteExit, RemoteWatch};
+use crate::prompt;
+use crate::{actions::commands::send_command, config::Config, logger::Logger};
 use chrono::Duration;
 use colored::Colorize;
-use russh::Disconnect;
+use futures::{stream, StreamExt};
+use regex::Regex;
+use std::cell::Cell;
 use std::collections::HashSet;
 use tokio::time::Instant;
-use russh_sftp::client::SftpSession;
 
-pub async fn execute_actions(logger: &mut Logger, config: Config, skip: HashSet<String>) {
-    let start_time = Instant::now();
-    for action in &config.actions {
-        match action {
-            Action::Commands { name, commands } => {
-                if skip.contains(name) {
-                    continue;
+pub fn remote_watch(action: &Action) -> Result<RemoteWatch, String> {
+    let Action::Commands {
+        detach_on,
+        detach_after,
+        keep_running,
+        fail_on,
+        expect_output,
+        ..
+    } = action
+    else {
+        return Ok(RemoteWatch::default());
+    };
+    Ok(RemoteWatch {
+        detach_on: detach_on
+            .as_deref()
+            .map(Regex::new)
+            .transpose()
+            .map_err(|e| format!("invalid detach_on pattern: {}", e))?,
+        detach_after: detach_after
+            .as_deref()
+            .map(parse_duration)
+            .transpose()
+            .map_err(|e| format!("invalid detach_after: {}", e))?,
+        keep_running: *keep_running,
+        fail_on: fail_on
+            .iter()
+            .map(|pattern| Regex::new(pattern))
+            .collect::<Result<_, _>>()
+            .map_err(|e| format!("invalid fail_on pattern: {}", e))?,
+        expect_output: expect_output
+            .as_deref()
+            .map(Regex::new)
+            .transpose()
+            .map_err(|e| format!("invalid expect_output pattern: {}", e))?,
+    })
+}
+
+// Logs the start and end of the action, read back by --resume
+async fn run_action(
+    logger: &mut Logger,
+    connection: &mut Connection,
+    server: &Server,
+    action: &Action,
+) -> bool {
+    log!(logger, "{} {}", "Action:".bright_black(), action.name());
+    let success = execute_action(&mut *logger, connection, server, action).await;
+    if success {
+        log!(logger, "{} {}", "Finished:".bright_black(), action.name());
+    }
+    success
+}
+
+// Returns false if the action failed and the deployment of this host must stop
+async fn execute_action(
+    logger: &mut Logger,
+    connection: &mut Connection,
+    server: &Server,
+    action: &Action,
+) -> bool {
+    let watch = match remote_watch(action) {
+        Ok(watch) => watch,
+        Err(reason) => {
+            log!(
+                logger,
+                "{} action '{}' has an {}",
+                "Error:".bright_red(),
+                action.name(),
+                reason
+            );
+            return false;
+        }
+    };
+
+    match action {
+        Action::Commands {
+            name,
+            commands,
+            continue_on_error,
+            merge_stderr,
+            ..
+        } => {
+            let Some(channel) = connection.open_channel(&mut *logger).await else {
+                return false;
+            };
+            let failure =
+                match send_command(&mut *logger, channel, commands, *merge_stderr, &watch).await {
+                    RemoteExit::Status(code) if code != 0 => {
+                        Some(format!("exited with status {}", code))
+                    }
+                    RemoteExit::Signal(signal) => Some(format!("killed by signal {}", signal)),
+                    RemoteExit::Closed => Some("closed without an exit status".to_string()),
+                    RemoteExit::Failed(reason) => Some(reason),
+                    RemoteExit::Status(_) | RemoteExit::Detached => None,
+                };
+            match failure {
+                Some(reason) if *continue_on_error => {
+                    log!(
+                        logger,
+                        "{} action '{}' failed ({}), continuing",
+                        "Warning:".yellow(),
+                        name,
+                        reason
+                    );
+                    true
+                }
+                Some(reason) => {
+                    log!(
+                        logger,
+                        "{} action '{}' failed ({})",
+                        "Error:".bright_red(),
+                        name,
+                        reason
+                    );
+                    false
                 }
-                let session = create_ssh_session(&config).await;
-                send_command(&mut *logger, &session, commands).await;
-                session
-                    .disconnect(Disconnect::ByApplication, "", "English")
-                    .await
-                    .expect("Failed to close ssh session");
+                None => true,
             }
-            Action::Upload {
-                name,
+        }
+        Action::Upload {
+            source_folder,
+            target_folder,
+            rendered,
+            ..
+        } => {
+            let Some(mut sftp) = connection.open_sftp(&mut *logger).await else {
+                return false;
+            };
+            let uploaded = upload(
+                server,
+                &mut *logger,
+                &mut sftp,
                 source_folder,
                 target_folder,
-            } => {
-                if skip.contains(name) {
-                    continue;
+                rendered,
+            )
+            .await;
+            if let Err(err) = sftp.close().await {
+                log!(
+                    logger,
+                    "{} unable to close the SFTP session: {}",
+                    "Warning:".yellow(),
+                    err
+                );
+            }
+            uploaded
+        }
+    }
+}
+
+// Runs every action targeting this host over a single connection
+async fn deploy_host(
+    logger: &mut Logger,
+    config: &Config,
+    host_name: &str,
+    skip: &HashSet<String>,
+) -> bool {
+    log!(
+        logger,
+        "{} {}",
+        "Host:".bright_black(),
+        host_name.bright_green()
+    );
+    let server = &config.servers[host_name];
+    let mut connection = Connection::new(server);
+    let mut success = true;
+    for action in &config.actions {
+        if skip.contains(action.name()) {
+            continue;
+        }
+        // targets were checked when loading the config
+        let hosts = config.action_hosts(action).unwrap_or_default();
+        if !hosts.iter().any(|name| name == host_name) {
+            continue;
+        }
+        if !run_action(&mut *logger, &mut connection, server, action).await {
+            success = false;
+            break;
+        }
+    }
+    connection.close(&mut *logger).await;
+    success
+}
+
+// Deploys the hosts at most `parallel` at a time, each with its own prefixed
+// logger when more than one runs at once. With stop_on_failure, no new host
+// is started after a failure, those are reported as None.
+async fn deploy_hosts(
+    logger: &mut Logger,
+    config: &Config,
+    host_names: &[String],
+    skip: &HashSet<String>,
+    parallel: usize,
+    stop_on_failure: bool,
+) -> Vec<(String, Option<bool>)> {
+    if parallel <= 1 || host_names.len() <= 1 {
+        let mut results = Vec::new();
+        let mut aborted = false;
+        for host_name in host_names {
+            if aborted && stop_on_failure {
+                results.push((host_name.clone(), None));
+                continue;
+            }
+            let success = deploy_host(&mut *logger, config, host_name, skip).await;
+            aborted = !success;
+            results.push((host_name.clone(), Some(success)));
+        }
+        return results;
+    }
+
+    let base_logger: &Logger = logger;
+    let aborted = Cell::new(false);
+    let mut results: Vec<(String, Option<bool>)> = stream::iter(host_names)
+        .map(|host_name| {
+            let aborted = &aborted;
+            async move {
+                if aborted.get() && stop_on_failure {
+                    return (host_name.clone(), None);
+                }
+                let mut host_logger = base_logger.for_host(host_name);
+                let success = deploy_host(&mut host_logger, config, host_name, skip).await;
+                if !success {
+                    aborted.set(true);
+                }
+                (host_name.clone(), Some(success))
+            }
+        })
+        .buffer_unordered(parallel)
+        .collect()
+        .await;
+    results.sort_by_key(|(host_name, _)| config.servers.get_index_of(host_name));
+    results
+}
+
+// Rolling deployment, each batch must be finished before the next one starts
+// and the remaining batches are aborted once the failed servers exceed
+// max_fail_percentage
+async fn deploy_in_batches(
+    logger: &mut Logger,
+    config: &Config,
+    host_names: &[String],
+    skip: &HashSet<String>,
+    parallel: Option<usize>,
+    serial: &Serial,
+) -> Vec<(String, Option<bool>)> {
+    // checked when loading the config
+    let batch_size = serial.batch_size(host_names.len()).unwrap_or(1);
+    let max_fail_percentage = config.max_fail_percentage.unwrap_or(0.0);
+    let batches: Vec<&[String]> = host_names.chunks(batch_size).collect();
+
+    let mut results = Vec::new();
+    let mut failed = 0;
+    let mut aborted = false;
+    for (index, batch) in batches.iter().enumerate() {
+        if aborted {
+            results.extend(batch.iter().map(|host_name| (host_name.clone(), None)));
+            continue;
+        }
+        log!(
+            logger,
+            "{} {}/{}: {}",
+            "Batch".bright_black(),
+            index + 1,
+            batches.len(),
+            batch.join(", ")
+        );
+        let batch_results = deploy_hosts(
+            &mut *logger,
+            config,
+            batch,
+            skip,
+            parallel.unwrap_or(batch.len()),
+            false,
+        )
+        .await;
+        let batch_failed = batch_results
+            .iter()
+            .filter(|(_, success)| *success != Some(true))
+            .count();
+        failed += batch_failed;
+        results.extend(batch_results);
+
+        let failed_percentage = failed as f64 * 100.0 / host_names.len() as f64;
+        aborted = failed_percentage > max_fail_percentage;
+        log!(
+            logger,
+            "{} {}/{} {}: {} ok, {} failed{}",
+            "Batch".bright_black(),
+            index + 1,
+            batches.len(),
+            if aborted { "aborted" } else { "finished" },
+            batch.len() - batch_failed,
+            batch_failed,
+            if aborted {
+                format!(
+                    ", {:.0}% of servers failed, over max_fail_percentage",
+                    failed_percentage
+                )
+            } else {
+                String::new()
+            }
+        );
+    }
+    results
+}
+
+// Runs the verify actions on the canary, then asks whether to deploy the
+// other servers unless the verification is meant to decide alone
+async fn check_canary(
+    logger: &mut Logger,
+    config: &Config,
+    canary: &Canary,
+    host_name: &str,
+    remaining: usize,
+) -> bool {
+    if !canary.verify.is_empty() {
+        log!(
+            logger,
+            "{} verifying '{}'",
+            "Canary:".bright_black(),
+            host_name
+        );
+        let server = &config.servers[host_name];
+        let mut connection = Connection::new(server);
+        for action in &canary.verify {
+            if !run_action(&mut *logger, &mut connection, server, action).await {
+                connection.close(&mut *logger).await;
+                log!(
+                    logger,
+                    "{} verification of '{}' failed",
+                    "Canary:".bright_black(),
+                    host_name
+                );
+                return false;
+            }
+        }
+        connection.close(&mut *logger).await;
+    }
+
+    if !canary.confirm.unwrap_or(canary.verify.is_empty()) {
+        return true;
+    }
+    if logger.is_plain() {
+        log!(
+            logger,
+            "{} the canary needs a confirmation but there is no terminal, add verify actions or set confirm = false",
+            "Error:".bright_red()
+        );
+        return false;
+    }
+    let question = format!(
+        "Canary '{}' deployed, continue with the {} other servers?",
+        host_name, remaining
+    );
+    let confirmed = tokio::task::block_in_place(|| prompt::confirm(&question));
+    log!(
+        logger,
+        "{} {}",
+        "Canary:".bright_black(),
+        if confirmed {
+            "confirmed"
+        } else {
+            "not confirmed"
+        }
+    );
+    confirmed
+}
+
+fn summarize(results: &[(String, Option<bool>)]) -> String {
+    results
+        .iter()
+        .map(|(host_name, success)| {
+            format!(
+                "{} {}",
+                host_name,
+                match success {
+                    Some(true) => "ok",
+                    Some(false) => "failed",
+                    None => "not deployed",
                 }
-                let session = create_ssh_session(&config).await;
+            )
+        })
+        .collect::<Vec<String>>()
+        .join(", ")
+}
 
-                let channel = session.channel_open_session().await.unwrap();
-                channel.request_subsystem(true, "sftp").await.unwrap();
-                let mut sftp = SftpSession::new(channel.into_stream()).await.expect("Unable to connect in SFTP");
-                upload(
-                    &config,
+// Returns false if the deployment failed on any host, `parallel` overrides
+// max_parallel from the config. When deploying in batches, the servers of a
+// batch all run at once unless a parallelism is set.
+pub async fn execute_actions(
+    logger: &mut Logger,
+    config: Config,
+    skip: HashSet<String>,
+    parallel: Option<usize>,
+) -> bool {
+    let start_time = Instant::now();
+    let parallel = parallel.or(config.max_parallel);
+    let mut host_names: Vec<String> = config.servers.keys().cloned().collect();
+    let mut results = Vec::new();
+
+    if let Some(canary) = config.canary() {
+        // checked when loading the config
+        let canary_host = canary.host.clone().unwrap_or_default();
+        host_names.retain(|host_name| *host_name != canary_host);
+        log!(
+            logger,
+            "{} deploying '{}' first",
+            "Canary:".bright_black(),
+            canary_host
+        );
+        let success = deploy_host(&mut *logger, &config, &canary_host, &skip).await
+            && check_canary(
+                &mut *logger,
+                &config,
+                &canary,
+                &canary_host,
+                host_names.len(),
+            )
+            .await;
+        results.push((canary_host, Some(success)));
+        if !success {
+            results.extend(host_names.drain(..).map(|host_name| (host_name, None)));
+        }
+    }
+
+    if !host_names.is_empty() {
+        results.extend(match &config.serial {
+            Some(serial) => {
+                deploy_in_batches(&mut *logger, &config, &host_names, &skip, parallel, serial).await
+            }
+            None => {
+                deploy_hosts(
                     &mut *logger,
-                    &mut sftp,
-                    source_folder,
-                    target_folder,
+                    &config,
+                    &host_names,
+                    &skip,
+                    parallel.unwrap_or(1),
+                    true,
                 )
-                .await;
-                sftp.close().await.expect("Failed to close sftp session");
+                .await
             }
-        }
+        });
+    }
+
+    if config.servers.len() > 1 {
+        log!(
+            logger,
+            "{} {}",
+            "Summary:".bright_black(),
+            summarize(&results)
+        );
+    }
+    if results.iter().any(|(_, success)| *success != Some(true)) {
+        log!(logger, "{} deployment aborted", "Error:".bright_red());
+        return false;
     }
 
     let chrono_duration = Duration::seconds(start_time.elapsed().as_secs() as i64);
@@ -64,4 +480,5 @@ pub async fn execute_actions(logger: &mut Logger, config: Config, skip: HashSet<
     };
 
     println!("{} finished in {}", "Done:".bright_black(), formatted_time);
+    true
 }
@@ -1,7 +1,8 @@
+use crate::interpolate::Context;
 use crate::Logger;
-use crate::{config::Config, log};
+use crate::{config::Server, log};
 use async_std::fs::File;
-use async_std::io::ReadExt;
+use async_std::io::{Read, ReadExt};
 use colored::*;
 use crossterm::style::{Color, Print, SetForegroundColor};
 use crossterm::terminal::ClearType::CurrentLine;
@@ -12,8 +13,11 @@ use crossterm::{
     execute,
 };
 use dirs_next::home_dir;
+use ignore::overrides::OverrideBuilder;
 use ignore::WalkBuilder;
 use russh_sftp::client::SftpSession;
+use std::collections::HashMap;
+use std::fs;
 use std::future::Future;
 use std::io::{stdout, Error, ErrorKind, Write};
 use std::path::{Path, PathBuf};
@@ -32,7 +36,7 @@ pub fn expand_user_path(user_path: &str) -> String {
     }
 }
 
-fn expand_server_path(server_path: &str, username: &str) -> String {
+pub fn expand_server_path(server_path: &str, username: &str) -> String {
     if server_path.starts_with("~/") {
         server_path.replacen("~", &format!("/home/{}", username), 1)
     } else {
@@ -42,6 +46,55 @@ fn expand_server_path(server_path: &str, username: &str) -> String {
 
 const CHUNK_SIZE: usize = 8 * 1024;
 
+// Walks the files of the source folder, minus those matched by its
+// .deployignore
+pub fn source_walker(source_folder: &str) -> WalkBuilder {
+    let mut builder = WalkBuilder::new(source_folder);
+    builder.standard_filters(false);
+    let ignore_path = Path::new(source_folder).join(".deployignore");
+    if ignore_path.exists() {
+        builder.add_ignore(ignore_path);
+    }
+    builder
+}
+
+// Contents of the files of the source folder matched by the templates, with
+// their ${...} placeholders replaced, by path relative to the source folder
+pub fn render_templates(
+    source_folder: &str,
+    templates: &[String],
+    context: &Context,
+) -> Result<HashMap<PathBuf, String>, String> {
+    let source_folder = expand_user_path(source_folder);
+    let mut builder = OverrideBuilder::new(&source_folder);
+    for template in templates {
+        builder
+            .add(template)
+            .map_err(|e| format!("invalid pattern '{}': {}", template, e))?;
+    }
+    let matcher = builder.build().map_err(|e| e.to_string())?;
+
+    let mut rendered = HashMap::new();
+    for entry in source_walker(&source_folder).build().flatten() {
+        let path = entry.path();
+        if !path.is_file() || !matcher.matched(path, false).is_whitelist() {
+            continue;
+        }
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("unable to read '{}': {}", path.display(), e))?;
+        let contents = context
+            .interpolate(&contents)
+            .map_err(|reason| format!("'{}': {}", path.display(), reason))?;
+        if let Ok(relative_path) = path.strip_prefix(&source_folder) {
+            rendered.insert(relative_path.to_path_buf(), contents);
+        }
+    }
+    if rendered.is_empty() {
+        return Err(format!("no file of '{}' matches", source_folder));
+    }
+    Ok(rendered)
+}
+
 async fn ensure_directory_exists(sftp: &mut SftpSession, file_path: &PathBuf) -> Result<(), Error> {
     if let Some(parent_path) = file_path.parent() {
         create_dir_recursive(sftp, parent_path.to_path_buf()).await
@@ -78,7 +131,11 @@ fn create_dir_recursive<'a>(
     })
 }
 
-fn rewrite(message: String) {
+fn rewrite(logger: &Logger, message: String) {
+    if logger.is_plain() {
+        logger.print(&message);
+        return;
+    }
     let mut writer = stdout();
     writer.execute(MoveUp(1)).unwrap();
     writer
@@ -103,13 +160,99 @@ fn progress_str(progress: f64) -> String {
     }
 }
 
+// Sends one file, or its rendered contents for templates, its progress is
+// shown unless in plain mode
+async fn upload_file(
+    logger: &mut Logger,
+    sftp: &mut SftpSession,
+    path: &Path,
+    relative_path: &Path,
+    target_path: &Path,
+    rendered: Option<&String>,
+) -> Result<(), String> {
+    let plain = logger.is_plain();
+    // Log the file transfer
+    if !plain {
+        println!(
+            "{} '{}'",
+            "▏".bright_cyan(),
+            relative_path.display().to_string().bright_black()
+        );
+    }
+
+    ensure_directory_exists(sftp, &target_path.to_path_buf())
+        .await
+        .map_err(|err| format!("Failed to ensure directory exists: {}", err))?;
+
+    let mut target_file = sftp
+        .create(target_path.to_string_lossy())
+        .await
+        .map_err(|err| format!("unable to create '{}': {}", target_path.display(), err))?;
+    // Open the source file, or read the rendered template
+    let (mut source, total_size): (Box<dyn Read + Send + Unpin + '_>, usize) = match rendered {
+        Some(contents) => (Box::new(contents.as_bytes()), contents.len()),
+        None => {
+            let source_file = File::open(path).await.map_err(|err| {
+                format!("Unable to open source file, {}: {}", path.display(), err)
+            })?;
+            let metadata = source_file.metadata().await.map_err(|err| {
+                format!("Unable to read file metadata, {}: {}", path.display(), err)
+            })?;
+            (Box::new(source_file), metadata.len() as usize)
+        }
+    };
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let mut uploaded = 0;
+
+    // Read and write in chunks
+    loop {
+        let bytes_read = source
+            .read(&mut buffer)
+            .await
+            .map_err(|err| format!("Unable to read {}: {}", path.display(), err))?;
+        if bytes_read == 0 {
+            break;
+        };
+        target_file
+            .write_all(&buffer[..bytes_read])
+            .await
+            .map_err(|err| format!("Error writing to '{}': {}", target_path.display(), err))?;
+        uploaded += bytes_read;
+
+        if plain {
+            continue;
+        }
+        let upload_ratio = uploaded as f64 / total_size as f64;
+        rewrite(
+            logger,
+            format!(
+                "{} '{}' ({:.2}%)",
+                progress_str(upload_ratio).bright_cyan(),
+                relative_path.display().to_string().bright_black(),
+                upload_ratio * 100.
+            ),
+        );
+    }
+    target_file
+        .sync_all()
+        .await
+        .map_err(|err| format!("Unable to sync '{}': {}", target_path.display(), err))?;
+
+    logger
+        .add_uploaded_file(relative_path.display().to_string())
+        .await;
+    Ok(())
+}
+
+// Returns false at the first file that could not be uploaded
 pub async fn upload(
-    config: &Config,
+    server: &Server,
     logger: &mut Logger,
     sftp: &mut SftpSession,
     source_folder: &String,
     target_folder: &String,
-) {
+    rendered: &HashMap<PathBuf, String>,
+) -> bool {
     log!(
         logger,
         "{}{}{}{}{}",
@@ -122,9 +265,10 @@ pub async fn upload(
     let source_folder = expand_user_path(&source_folder);
     let mut ignore_path = Path::new(&source_folder).to_path_buf();
     ignore_path.push(".deployignore");
-    let mut builder = WalkBuilder::new(&source_folder);
-    builder.standard_filters(false);
-    if ignore_path.exists() {
+    let plain = logger.is_plain();
+    if ignore_path.exists() && plain {
+        logger.print(&format!("Found: '{}'", ignore_path.display()));
+    } else if ignore_path.exists() {
         execute!(
             stdout(),
             Clear(ClearType::CurrentLine),
@@ -139,89 +283,41 @@ pub async fn upload(
             MoveToColumn(0),
         )
         .unwrap();
-
-        builder.add_ignore(ignore_path);
     }
 
-    let target_folder = expand_server_path(target_folder, &config.server.user);
-    for result in builder.build() {
-        match result {
-            Ok(entry) => {
+    let target_folder = expand_server_path(target_folder, server.user());
+    let mut result = Ok(());
+    for entry in source_walker(&source_folder).build() {
+        result = match entry {
+            Ok(entry) if entry.path().is_file() => {
                 let path = entry.path();
-                if path.is_file() {
-                    // Compute relative path
-                    let relative_path = path.strip_prefix(&source_folder).unwrap();
-                    let target_path = Path::new(&target_folder).join(relative_path);
-
-                    // Log the file transfer
-                    println!(
-                        "{} '{}'",
-                        "▏".bright_cyan(),
-                        relative_path.display().to_string().bright_black()
-                    );
-
-                    if let Err(err) = ensure_directory_exists(sftp, &target_path).await {
-                        rewrite(format!(
-                            "{} Failed to ensure directory exists: {}",
-                            "Error:".bright_red(),
-                            err
-                        ))
-                    }
-
-                    match sftp.create(target_path.as_path().to_string_lossy()).await {
-                        Ok(mut target_file) => {
-                            // Open the source file
-                            if let Ok(mut source_file) = File::open(&path).await {
-                                let metadata = source_file
-                                    .metadata()
-                                    .await
-                                    .expect("Unable to read file metadata");
-
-                                let total_size = metadata.len() as usize;
-                                let mut buffer = vec![0; CHUNK_SIZE];
-                                let mut uploaded = 0;
-
-                                // Read and write in chunks
-                                while let Ok(bytes_read) = source_file.read(&mut buffer).await {
-                                    if bytes_read == 0 {
-                                        break;
-                                    };
-                                    target_file
-                                        .write_all(&buffer[..bytes_read])
-                                        .await
-                                        .expect("Error writing to file");
-                                    uploaded += bytes_read;
-
-                                    let upload_ratio = uploaded as f64 / total_size as f64;
-                                    rewrite(format!(
-                                        "{} '{}' ({:.2}%)",
-                                        progress_str(upload_ratio).bright_cyan(),
-                                        relative_path.display().to_string().bright_black(),
-                                        upload_ratio * 100.
-                                    ));
-                                }
-                                target_file
-                                    .sync_all()
-                                    .await
-                                    .expect("Unable to sync file write");
-
-                                logger
-                                    .add_uploaded_file(relative_path.display().to_string())
-                                    .await;
-                            } else {
-                                println!(
-                                    "{} Unable to open source file, {}",
-                                    "Error:".bright_red(),
-                                    path.display()
-                                );
-                            }
-                        }
-                        Err(err) => println!("{} {}", "Error:".bright_red(), err),
-                    }
-                }
+                // Compute relative path
+                let relative_path = path.strip_prefix(&source_folder).unwrap();
+                let target_path = Path::new(&target_folder).join(relative_path);
+                let contents = rendered.get(relative_path);
+                upload_file(
+                    &mut *logger,
+                    sftp,
+                    path,
+                    relative_path,
+                    &target_path,
+                    contents,
+                )
+                .await
             }
-            Err(err) => println!("{} {}", "Error:".bright_red(), err),
+            Ok(_) => Ok(()),
+            Err(err) => Err(err.to_string()),
+        };
+        if result.is_err() {
+            break;
         }
     }
     logger.stop_files_display().await;
+    match result {
+        Ok(()) => true,
+        Err(e) => {
+            log!(logger, "{} {}", "Error:".bright_red(), e);
+            false
+        }
+    }
 }
@@ -1,45 +1,1241 @@
+use crate::actions::{expand_user_path, render_templates};
+use crate::interpolate::{self, Context, Git};
+use crate::secrets;
+use crate::ssh_config;
+use crate::validate;
+use indexmap::IndexMap;
 use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
 use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 macro_rules! pub_struct {
-    ($($derive:path),*; $name:ident {$($field:ident: $t:ty),* $(,)?}) => {
+    ($($derive:path),*; $name:ident {$($(#[$attr:meta])* $field:ident: $t:ty),* $(,)?}) => {
         #[derive($($derive),*)]
         pub struct $name {
-            $(pub $field: $t),*
+            $($(#[$attr])* pub $field: $t),*
         }
     }
 }
 
-pub_struct!(Clone, Deserialize; Server {
+#[derive(Clone, Deserialize)]
+#[serde(untagged)]
+pub enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl OneOrMany {
+    pub fn to_vec(&self) -> Vec<String> {
+        match self {
+            OneOrMany::One(value) => vec![value.clone()],
+            OneOrMany::Many(values) => values.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(tag = "type")]
+pub enum AuthMethod {
+    #[serde(rename = "agent")]
+    Agent,
+    #[serde(rename = "key")]
+    Key {
+        path: String,
+        // prompted for when missing and the key is encrypted
+        passphrase_env: Option<String>,
+    },
+    #[serde(rename = "password")]
+    Password { password_env: Option<String> },
+    #[serde(rename = "keyboard-interactive")]
+    KeyboardInteractive,
+}
+
+pub_struct!(Clone, Default, Deserialize; Server {
+    // address such as "ssh://user@host:port", "host:port" or "[::1]:2222",
+    // or a ~/.ssh/config alias, defaults to ssh_config_host
+    #[serde(default)]
     host: String,
-    port : u16,
-    user: String,
-    ssh_key: String,
+    // 22 by default
+    port: Option<u16>,
+    user: Option<String>,
+    // alias to read HostName, Port, User, IdentityFile and ProxyJump from
+    // ~/.ssh/config, host itself is looked up when missing
+    ssh_config_host: Option<String>,
+    ssh_key: Option<String>,
+    // tried in order until one succeeds
+    auth: Option<Vec<AuthMethod>>,
+    // pinned keys, skips known_hosts when set
+    host_key_fingerprint: Option<OneOrMany>,
+    // hosts to hop through in order, ProxyJump from ~/.ssh/config when missing
+    jump: Option<Vec<Server>>,
+    // IdentityFile entries from ~/.ssh/config
+    #[serde(skip)]
+    identity_files: Vec<String>,
+    // ProxyJump from ~/.ssh/config
+    #[serde(skip)]
+    proxy_jump: Option<String>,
 });
 
+impl Server {
+    pub fn port(&self) -> u16 {
+        self.port.unwrap_or(22)
+    }
+
+    // always set once the config is loaded
+    pub fn user(&self) -> &str {
+        self.user.as_deref().unwrap_or_default()
+    }
+
+    pub fn auth_methods(&self) -> Vec<AuthMethod> {
+        if let Some(methods) = &self.auth {
+            return methods.clone();
+        }
+        if let Some(path) = &self.ssh_key {
+            return vec![AuthMethod::Key {
+                path: path.clone(),
+                passphrase_env: None,
+            }];
+        }
+        let mut methods = vec![AuthMethod::Agent];
+        methods.extend(self.identity_files.iter().map(|path| AuthMethod::Key {
+            path: path.clone(),
+            passphrase_env: None,
+        }));
+        methods
+    }
+
+    // Completes the fields left out with the matching ~/.ssh/config entry,
+    // values from the deploy config take precedence
+    fn resolve_ssh_config(&mut self, name: &str) -> Result<(), String> {
+        self.resolve_host(name, None)?;
+
+        let mut jump = match (self.jump.take(), &self.proxy_jump) {
+            (Some(jump), _) => jump,
+            (None, Some(proxy_jump)) => parse_proxy_jump(proxy_jump),
+            (None, None) => Vec::new(),
+        };
+        for (index, hop) in jump.iter_mut().enumerate() {
+            let hop_name = format!("{} (jump {})", name, index + 1);
+            if hop.jump.is_some() {
+                return Err(format!(
+                    "jump hosts of '{}' cannot have their own, list every hop in order",
+                    name
+                ));
+            }
+            // hops use the user of the server unless set otherwise
+            hop.resolve_host(&hop_name, self.user.as_deref())?;
+        }
+        if !jump.is_empty() {
+            self.jump = Some(jump);
+        }
+        Ok(())
+    }
+
+    fn resolve_host(&mut self, name: &str, default_user: Option<&str>) -> Result<(), String> {
+        if !self.host.is_empty() {
+            let address = parse_address(&self.host)
+                .map_err(|reason| format!("server '{}' has an invalid host: {}", name, reason))?;
+            if let (Some(port), Some(url_port)) = (self.port, address.port) {
+                if port != url_port {
+                    return Err(format!(
+                        "server '{}' has port {} in host but port = {}",
+                        name, url_port, port
+                    ));
+                }
+            }
+            if let (Some(user), Some(url_user)) = (&self.user, &address.user) {
+                if user != url_user {
+                    return Err(format!(
+                        "server '{}' has user '{}' in host but user = '{}'",
+                        name, url_user, user
+                    ));
+                }
+            }
+            self.host = address.host;
+            self.port = self.port.or(address.port);
+            self.user = self.user.take().or(address.user);
+        }
+
+        let alias = match &self.ssh_config_host {
+            Some(alias) => alias.clone(),
+            None => self.host.clone(),
+        };
+        if alias.is_empty() {
+            return Err(format!(
+                "server '{}' needs a host or an ssh_config_host",
+                name
+            ));
+        }
+        let entry = ssh_config::lookup(&alias)?;
+
+        if self.host.is_empty() || self.ssh_config_host.is_none() {
+            self.host = entry.hostname.unwrap_or(alias);
+        }
+        self.port = self.port.or(entry.port);
+        self.user = self
+            .user
+            .take()
+            .or(entry.user)
+            .or(default_user.map(String::from));
+        self.identity_files = entry.identity_files;
+        self.proxy_jump = entry.proxy_jump;
+
+        if self.user.is_none() {
+            return Err(format!(
+                "server '{}' has no user, set user or a User in ~/.ssh/config",
+                name
+            ));
+        }
+        Ok(())
+    }
+}
+
+struct Address {
+    user: Option<String>,
+    host: String,
+    port: Option<u16>,
+}
+
+// Splits "ssh://user@host:port", "user@host:port", "host:port", "[::1]:2222"
+// or a bare hostname or IP address, user and port being optional
+fn parse_address(value: &str) -> Result<Address, String> {
+    let mut rest = value.trim();
+    if let Some((scheme, address)) = rest.split_once("://") {
+        if scheme != "ssh" {
+            return Err(format!(
+                "unsupported scheme '{}://' in '{}', only ssh:// is accepted",
+                scheme, value
+            ));
+        }
+        rest = address.trim_end_matches('/');
+    }
+
+    let mut user = None;
+    if let Some((name, address)) = rest.rsplit_once('@') {
+        if name.is_empty() {
+            return Err(format!("empty user in '{}'", value));
+        }
+        user = Some(name.to_string());
+        rest = address;
+    }
+
+    let (host, port) = if let Some(bracketed) = rest.strip_prefix('[') {
+        let (host, after) = bracketed
+            .split_once(']')
+            .ok_or_else(|| format!("missing ']' after the IPv6 address in '{}'", value))?;
+        match after {
+            "" => (host, None),
+            _ => match after.strip_prefix(':') {
+                Some(port) => (host, Some(port)),
+                None => return Err(format!("unexpected '{}' after ']' in '{}'", after, value)),
+            },
+        }
+    } else if rest.matches(':').count() > 1 {
+        // IPv6 without brackets, which cannot carry a port
+        (rest, None)
+    } else {
+        match rest.split_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (rest, None),
+        }
+    };
+    if host.is_empty() {
+        return Err(format!("no host in '{}'", value));
+    }
+    let port = port
+        .map(|port| {
+            port.parse::<u16>()
+                .ok()
+                .filter(|port| *port != 0)
+                .ok_or_else(|| format!("invalid port '{}' in '{}'", port, value))
+        })
+        .transpose()?;
+
+    Ok(Address {
+        user,
+        host: host.to_string(),
+        port,
+    })
+}
+
+// ProxyJump is a comma separated list of [user@]host[:port], parsed along
+// with the server
+fn parse_proxy_jump(value: &str) -> Vec<Server> {
+    value
+        .split(',')
+        .map(|hop| Server {
+            host: hop.trim().to_string(),
+            ..Server::default()
+        })
+        .collect()
+}
+
 #[derive(Clone, Deserialize)]
 #[serde(tag = "type")]
 pub enum Action {
     #[serde(rename = "commands")]
-    Commands { name: String, commands: Vec<String> },
+    Commands {
+        name: String,
+        commands: Vec<String>,
+        // keep deploying when the commands exit with a non-zero status
+        #[serde(default)]
+        continue_on_error: bool,
+        // send stderr through stdout instead of separately
+        #[serde(default)]
+        merge_stderr: bool,
+        // end the action successfully once a line of output matches
+        detach_on: Option<String>,
+        // end the action successfully after a duration such as "30s"
+        detach_after: Option<String>,
+        // leave the command running when detaching automatically
+        #[serde(default)]
+        keep_running: bool,
+        // fail the action when a line of output matches one of these
+        #[serde(default)]
+        fail_on: Vec<String>,
+        // fail the action unless a line of output matches
+        expect_output: Option<String>,
+        // servers or groups to run on, all servers by default
+        hosts: Option<OneOrMany>,
+    },
     #[serde(rename = "upload")]
     Upload {
         name: String,
         source_folder: String,
         target_folder: String,
+        // files of the source folder whose ${...} placeholders are replaced
+        // before they are sent, as .deployignore patterns such as "*.env"
+        #[serde(default)]
+        templates: Vec<String>,
+        // servers or groups to run on, all servers by default
+        hosts: Option<OneOrMany>,
+        // contents of the templates by path relative to the source folder,
+        // rendered when loading
+        #[serde(skip)]
+        rendered: HashMap<PathBuf, String>,
     },
 }
 
+impl Action {
+    pub fn name(&self) -> &str {
+        match self {
+            Action::Commands { name, .. } | Action::Upload { name, .. } => name,
+        }
+    }
+
+    pub fn hosts(&self) -> Option<&OneOrMany> {
+        match self {
+            Action::Commands { hosts, .. } | Action::Upload { hosts, .. } => hosts.as_ref(),
+        }
+    }
+}
+
+// Size of the batches of a rolling deployment, "25%" is relative to the
+// number of servers
+#[derive(Clone, Deserialize)]
+#[serde(untagged)]
+pub enum Serial {
+    Count(usize),
+    Percentage(String),
+}
+
+impl Serial {
+    pub fn batch_size(&self, total: usize) -> Result<usize, String> {
+        let size = match self {
+            Serial::Count(count) => *count,
+            Serial::Percentage(value) => {
+                let percentage: f64 = value
+                    .trim()
+                    .strip_suffix('%')
+                    .and_then(|number| number.trim().parse().ok())
+                    .filter(|percentage| *percentage > 0.0 && *percentage <= 100.0)
+                    .ok_or_else(|| {
+                        format!(
+                            "invalid serial '{}', expected a number of servers or a percentage such as \"25%\"",
+                            value
+                        )
+                    })?;
+                (total as f64 * percentage / 100.0).ceil() as usize
+            }
+        };
+        if size == 0 {
+            return Err("serial must be at least 1".to_string());
+        }
+        Ok(size.min(total))
+    }
+}
+
+pub_struct!(Clone, Deserialize; Canary {
+    // first server when missing
+    host: Option<String>,
+    // run on the canary once deployed, a failure aborts the deployment
+    #[serde(default)]
+    verify: Vec<Action>,
+    // ask before deploying the other servers, by default only when there
+    // are no verify actions
+    confirm: Option<bool>,
+});
+
+// `canary = true` (first server), `canary = "api-1"` or a [canary] table
+#[derive(Clone, Deserialize)]
+#[serde(untagged)]
+pub enum CanarySpec {
+    FirstHost(bool),
+    Host(String),
+    Full(Canary),
+}
+
+pub_struct!(Clone, Deserialize; Secrets {
+    // environment variables holding secrets
+    #[serde(default)]
+    env: Vec<String>,
+    // KEY=VALUE file, relative to the config file
+    dotenv: Option<String>,
+    // KEY=VALUE file encrypted with `deploy secrets edit`, relative to the
+    // config file
+    file: Option<String>,
+});
+
 pub_struct!(Clone, Deserialize; Config {
-    server: Server,
+    // single server shorthand, moved into servers when loading
+    server: Option<Server>,
+    #[serde(default)]
+    servers: IndexMap<String, Server>,
+    #[serde(default)]
+    groups: IndexMap<String, Vec<String>>,
+    // how many servers are deployed at the same time, 1 by default
+    max_parallel: Option<usize>,
+    // deploy servers in successive batches of this size
+    serial: Option<Serial>,
+    // abort the remaining batches once more servers than this have failed
+    max_fail_percentage: Option<f64>,
+    // deploy a single server first and check it before the others
+    canary: Option<CanarySpec>,
     actions: Vec<Action>,
+    // values of the [secrets], hidden from the output and the logs
+    #[serde(skip)]
+    secret_values: Vec<String>,
 });
 
-pub fn load(config_path: &str) -> Config {
-    let file_contents = fs::read_to_string(config_path).expect("error: unable to read file");
+impl Config {
+    // Canary settings with the host resolved, None when disabled
+    pub fn canary(&self) -> Option<Canary> {
+        let mut canary = match self.canary.as_ref()? {
+            CanarySpec::FirstHost(false) => return None,
+            CanarySpec::FirstHost(true) => Canary {
+                host: None,
+                verify: Vec::new(),
+                confirm: None,
+            },
+            CanarySpec::Host(host) => Canary {
+                host: Some(host.clone()),
+                verify: Vec::new(),
+                confirm: None,
+            },
+            CanarySpec::Full(canary) => canary.clone(),
+        };
+        if canary.host.is_none() {
+            canary.host = self.servers.keys().next().cloned();
+        }
+        Some(canary)
+    }
+
+    // Names of the servers an action runs on, in inventory order
+    pub fn action_hosts(&self, action: &Action) -> Result<Vec<String>, String> {
+        let targets = action
+            .hosts()
+            .map(|hosts| hosts.to_vec())
+            .unwrap_or_else(|| vec!["all".to_string()]);
+        let mut selected = HashSet::new();
+        for target in &targets {
+            if target == "all" {
+                selected.extend(self.servers.keys());
+            } else if let Some(members) = self.groups.get(target) {
+                selected.extend(members);
+            } else if self.servers.contains_key(target) {
+                selected.insert(target);
+            } else {
+                return Err(format!(
+                    "action '{}' targets '{}', which is neither a server nor a group",
+                    action.name(),
+                    target
+                ));
+            }
+        }
+        Ok(self
+            .servers
+            .keys()
+            .filter(|name| selected.contains(name))
+            .cloned()
+            .collect())
+    }
+
+    // Moves [server] into the servers and checks the inventory, every error
+    // found is returned
+    fn resolve_inventory(&mut self) -> Result<(), Vec<String>> {
+        if let Some(server) = self.server.take() {
+            if !self.servers.is_empty() {
+                return Err(vec![
+                    "use either [server] or [servers.<name>], not both".to_string()
+                ]);
+            }
+            let name = match &server.ssh_config_host {
+                Some(alias) if server.host.is_empty() => alias.clone(),
+                // keyed by the bare host rather than the full URL
+                _ => parse_address(&server.host)
+                    .map(|address| address.host)
+                    .unwrap_or_else(|_| server.host.clone()),
+            };
+            self.servers.insert(name, server);
+        }
+        if self.servers.is_empty() {
+            return Err(vec![
+                "no server defined, add a [server] or [servers.<name>] table".to_string(),
+            ]);
+        }
+
+        let mut errors = Vec::new();
+        for (name, server) in self.servers.iter_mut() {
+            if let Err(reason) = server.resolve_ssh_config(name) {
+                errors.push(reason);
+            }
+        }
+        for (group, members) in &self.groups {
+            if group == "all" || self.servers.contains_key(group) {
+                errors.push(format!(
+                    "group '{}' has the same name as a server or 'all'",
+                    group
+                ));
+            }
+            for unknown in members.iter().filter(|m| !self.servers.contains_key(*m)) {
+                errors.push(format!(
+                    "group '{}' contains '{}', which is not a server",
+                    group, unknown
+                ));
+            }
+        }
+        for action in &self.actions {
+            if let Err(reason) = self.action_hosts(action) {
+                errors.push(reason);
+            }
+        }
+        if let Some(canary) = self.canary() {
+            let host = canary.host.unwrap_or_default();
+            if !self.servers.contains_key(&host) {
+                errors.push(format!("canary '{}' is not a server", host));
+            }
+        }
+        if let Some(serial) = &self.serial {
+            if let Err(reason) = serial.batch_size(self.servers.len()) {
+                errors.push(reason);
+            }
+        }
+        if let Some(percentage) = self.max_fail_percentage {
+            if !(0.0..=100.0).contains(&percentage) {
+                errors.push("max_fail_percentage must be between 0 and 100".to_string());
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+// Sets the variable once the variables it references are set. `stack` holds
+// the variables being resolved to detect cycles.
+fn resolve_var(
+    name: &str,
+    raw: &IndexMap<String, String>,
+    context: &mut Context,
+    resolved: &mut HashSet<String>,
+    stack: &mut Vec<String>,
+) -> Result<(), String> {
+    if resolved.contains(name) {
+        return Ok(());
+    }
+    if stack.iter().any(|var| var == name) {
+        let cycle: Vec<&str> = stack
+            .iter()
+            .map(String::as_str)
+            .skip_while(|var| *var != name)
+            .chain(std::iter::once(name))
+            .collect();
+        return Err(format!(
+            "vars.{}: variables reference each other: {}",
+            name,
+            cycle.join(" -> ")
+        ));
+    }
+    stack.push(name.to_string());
+    for reference in interpolate::references(&raw[name]) {
+        if raw.contains_key(&reference) {
+            resolve_var(&reference, raw, context, resolved, stack)?;
+        }
+    }
+    stack.pop();
+
+    let value = context
+        .interpolate(&raw[name])
+        .map_err(|reason| format!("vars.{}: {}", name, reason))?;
+    context.set(name, &value);
+    resolved.insert(name.to_string());
+    Ok(())
+}
+
+// Takes the [vars] table out of the document, its values may use other
+// variables, ${env.NAME} and ${git.*}. `overrides` come from --var and take
+// precedence.
+fn resolve_vars(
+    document: &mut toml::Table,
+    overrides: &[(String, String)],
+    context: &mut Context,
+) -> Result<(), String> {
+    let vars = match document.remove("vars") {
+        Some(toml::Value::Table(vars)) => vars,
+        Some(_) => return Err("vars must be a table".to_string()),
+        None => toml::Table::new(),
+    };
+    // overrides are used as is
+    for (name, value) in overrides {
+        context.set(name, value);
+    }
+    let mut raw = IndexMap::new();
+    for (name, value) in vars {
+        if overrides.iter().any(|(overridden, _)| *overridden == name) {
+            continue;
+        }
+        let value = match value {
+            toml::Value::String(string) => string,
+            toml::Value::Integer(_) | toml::Value::Float(_) | toml::Value::Boolean(_) => {
+                value.to_string()
+            }
+            _ => {
+                return Err(format!(
+                    "vars.{} must be a string, a number or a boolean",
+                    name
+                ))
+            }
+        };
+        raw.insert(name, value);
+    }
+
+    let mut resolved = HashSet::new();
+    for name in raw.keys() {
+        resolve_var(name, &raw, context, &mut resolved, &mut Vec::new())?;
+    }
+    Ok(())
+}
+
+// Deep-merges the overlay into the base, actions are matched by name and
+// appended when new, other arrays are replaced
+fn merge(base: &mut toml::Value, overlay: toml::Value, path: &str) {
+    match (base, overlay) {
+        (toml::Value::Table(base), toml::Value::Table(overlay)) => {
+            merge_tables(base, overlay, path)
+        }
+        (toml::Value::Array(base), toml::Value::Array(overlay)) if path == "actions" => {
+            for action in overlay {
+                let name = action.get("name").cloned();
+                match base
+                    .iter_mut()
+                    .find(|existing| name.is_some() && existing.get("name") == name.as_ref())
+                {
+                    Some(existing) => merge(existing, action, ""),
+                    None => base.push(action),
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+fn merge_tables(base: &mut toml::Table, overlay: toml::Table, path: &str) {
+    for (key, value) in overlay {
+        let path = match path {
+            "" => key.clone(),
+            _ => format!("{}.{}", path, key),
+        };
+        match base.get_mut(&key) {
+            Some(existing) => merge(existing, value, &path),
+            None => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+// "app.deploy.toml" gives "app.prod.deploy.toml", other names get the
+// environment before their extension
+fn sibling_path(config_path: &str, environment: &str) -> PathBuf {
+    let path = Path::new(config_path);
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let sibling = match file_name.strip_suffix(".deploy.toml") {
+        Some(stem) => format!("{}.{}.deploy.toml", stem, environment),
+        None => match file_name.rsplit_once('.') {
+            Some((stem, extension)) => format!("{}.{}.{}", stem, environment, extension),
+            None => format!("{}.{}", file_name, environment),
+        },
+    };
+    path.with_file_name(sibling)
+}
+
+// Applies the [env.<name>] section or the sibling file of the environment,
+// the [env] sections are dropped from the document either way
+fn apply_environment(
+    document: &mut toml::Table,
+    config_path: &str,
+    environment: Option<&str>,
+    files: &mut Vec<(PathBuf, String)>,
+) -> Result<(), String> {
+    let mut sections = match document.remove("env") {
+        Some(toml::Value::Table(sections)) => sections,
+        Some(_) => return Err("env must be a table of environments".to_string()),
+        None => toml::Table::new(),
+    };
+    let Some(environment) = environment else {
+        return Ok(());
+    };
+
+    let section = sections.remove(environment);
+    let sibling = sibling_path(config_path, environment);
+    let overlay = match (section, sibling.exists()) {
+        (Some(_), true) => {
+            return Err(format!(
+                "environment '{}' is defined both in [env.{}] and '{}', keep only one",
+                environment,
+                environment,
+                sibling.display()
+            ))
+        }
+        (Some(section), false) => section,
+        (None, true) => {
+            // read like the config itself, with its extends and includes
+            let read = files.len();
+            let overlay = read_document(&sibling, &mut Vec::new(), files)?;
+            // takes precedence over the other files when locating errors
+            let sibling_files = files.split_off(read);
+            files.splice(0..0, sibling_files);
+            toml::Value::Table(overlay)
+        }
+        (None, false) => {
+            return Err(format!(
+                "environment '{}' not found, add an [env.{}] section or '{}'",
+                environment,
+                environment,
+                sibling.display()
+            ))
+        }
+    };
+    let toml::Value::Table(overlay) = overlay else {
+        return Err(format!("env.{} must be a table", environment));
+    };
+    if overlay.contains_key("env") {
+        return Err(format!(
+            "environment '{}' cannot define environments itself",
+            environment
+        ));
+    }
+    merge_tables(document, overlay, "");
+    Ok(())
+}
+
+// Paths in extends, includes and secrets are relative to the file using them
+fn relative_to(including: &Path, value: &toml::Value, key: &str) -> Result<PathBuf, String> {
+    let path = value.as_str().ok_or_else(|| {
+        format!(
+            "{} in '{}' must be a path to a file",
+            key,
+            including.display()
+        )
+    })?;
+    let path = PathBuf::from(expand_user_path(path));
+    Ok(match including.parent() {
+        Some(parent) if path.is_relative() => parent.join(path),
+        _ => path,
+    })
+}
+
+// Makes the files of a [secrets] table relative to the file declaring it,
+// before it is merged with others
+fn resolve_secret_paths(secrets: Option<&mut toml::Value>, path: &Path) -> Result<(), String> {
+    let Some(toml::Value::Table(secrets)) = secrets else {
+        return Ok(());
+    };
+    for key in ["dotenv", "file"] {
+        if let Some(value) = secrets.get_mut(key) {
+            let resolved = relative_to(path, value, key)?;
+            *value = toml::Value::String(resolved.to_string_lossy().to_string());
+        }
+    }
+    Ok(())
+}
+
+// Replaces the include actions of a file by the actions of the files they
+// include
+fn expand_includes(
+    actions: Option<&mut toml::Value>,
+    path: &Path,
+    stack: &mut Vec<PathBuf>,
+    files: &mut Vec<(PathBuf, String)>,
+) -> Result<(), String> {
+    let Some(toml::Value::Array(actions)) = actions else {
+        return Ok(());
+    };
+    let mut expanded = Vec::new();
+    for action in std::mem::take(actions) {
+        if action.get("type").and_then(|t| t.as_str()) != Some("include") {
+            expanded.push(action);
+            continue;
+        }
+        let file = action
+            .get("file")
+            .ok_or_else(|| format!("include action in '{}' needs a file", path.display()))?;
+        let included = relative_to(path, file, "file")?;
+        match read_document(&included, stack, files)?.remove("actions") {
+            Some(toml::Value::Array(actions)) => expanded.extend(actions),
+            _ => {
+                return Err(format!(
+                    "'{}' is included but has no actions",
+                    included.display()
+                ))
+            }
+        }
+    }
+    *actions = expanded;
+    Ok(())
+}
+
+// Reads a config file with its `extends` merged under it and its include
+// actions replaced by the actions of the included files. `stack` holds the
+// files being read to detect cycles, `files` gets the contents of every file
+// read to locate errors.
+fn read_document(
+    path: &Path,
+    stack: &mut Vec<PathBuf>,
+    files: &mut Vec<(PathBuf, String)>,
+) -> Result<toml::Table, String> {
+    let canonical = path
+        .canonicalize()
+        .map_err(|e| format!("unable to read '{}': {}", path.display(), e))?;
+    if stack.contains(&canonical) {
+        let cycle: Vec<String> = stack
+            .iter()
+            .skip_while(|file| **file != canonical)
+            .chain(std::iter::once(&canonical))
+            .map(|file| file.display().to_string())
+            .collect();
+        return Err(format!("include cycle: {}", cycle.join(" -> ")));
+    }
+
+    let file_contents = fs::read_to_string(path)
+        .map_err(|e| format!("unable to read '{}': {}", path.display(), e))?;
+    let mut document: toml::Table = toml::from_str(&file_contents)
+        .map_err(|e| format!("unable to parse '{}': {}", path.display(), e))?;
+    files.push((path.to_path_buf(), file_contents));
+    stack.push(canonical);
+
+    resolve_secret_paths(document.get_mut("secrets"), path)?;
+    expand_includes(document.get_mut("actions"), path, stack, files)?;
+    if let Some(toml::Value::Table(environments)) = document.get_mut("env") {
+        for (_, environment) in environments.iter_mut() {
+            resolve_secret_paths(environment.get_mut("secrets"), path)?;
+            expand_includes(environment.get_mut("actions"), path, stack, files)?;
+        }
+    }
+
+    if let Some(parent) = document.remove("extends") {
+        let parent = relative_to(path, &parent, "extends")?;
+        let mut merged = read_document(&parent, stack, files)?;
+        merge_tables(&mut merged, document, "");
+        document = merged;
+    }
 
-    let config: Config =
-        toml::from_str(&file_contents).expect("error: unable to deserialize config");
+    stack.pop();
+    Ok(document)
+}
 
-    config
+// Renders the templates of an upload action, skipped when its source folder
+// is missing since validate reports it
+fn render_action_templates(action: &mut Action, context: &Context) -> Result<(), String> {
+    let Action::Upload {
+        name,
+        source_folder,
+        templates,
+        rendered,
+        ..
+    } = action
+    else {
+        return Ok(());
+    };
+    if templates.is_empty() || !Path::new(&expand_user_path(source_folder)).is_dir() {
+        return Ok(());
+    }
+    *rendered = render_templates(source_folder, templates, context)
+        .map_err(|reason| format!("action '{}' templates: {}", name, reason))?;
+    Ok(())
+}
+
+// Takes the [secrets] table out of the document and reads the secrets it
+// lists, available as ${secret.NAME}
+fn resolve_secrets(
+    document: &mut toml::Table,
+    context: &mut Context,
+) -> Result<Vec<String>, String> {
+    let Some(table) = document.remove("secrets") else {
+        return Ok(Vec::new());
+    };
+    let table: Secrets = table
+        .try_into()
+        .map_err(|e| format!("invalid secrets: {}", e))?;
+    let mut values = Vec::new();
+    for (name, value) in secrets::load(&table)? {
+        context.set_secret(&name, &value);
+        values.push(value);
+    }
+    Ok(values)
+}
+
+// Errors found while loading the config, along with the config when it could
+// still be deserialized so that the other checks can run on it, and the
+// secrets read so far to hide them from the errors
+pub struct LoadError {
+    pub config: Option<Box<Config>>,
+    pub errors: Vec<String>,
+    pub secret_values: Vec<String>,
+}
+
+impl From<String> for LoadError {
+    fn from(error: String) -> LoadError {
+        LoadError {
+            config: None,
+            errors: vec![error],
+            secret_values: Vec::new(),
+        }
+    }
+}
+
+// Reads the config with its extends, includes, environment, secrets and
+// variables applied. Every error found is returned, except when a file
+// cannot be read or parsed.
+pub fn load(
+    config_path: &str,
+    environment: Option<&str>,
+    overrides: &[(String, String)],
+    git: Git,
+) -> Result<Config, LoadError> {
+    let mut files = Vec::new();
+    let mut document = read_document(Path::new(config_path), &mut Vec::new(), &mut files)?;
+    apply_environment(&mut document, config_path, environment, &mut files)?;
+    let mut context = Context::new(git);
+    let secret_values = resolve_secrets(&mut document, &mut context)?;
+    let failed = |config: Option<Box<Config>>, errors: Vec<String>| LoadError {
+        config,
+        errors,
+        secret_values: secret_values.clone(),
+    };
+    resolve_vars(&mut document, overrides, &mut context).map_err(|e| failed(None, vec![e]))?;
+
+    let unknown = validate::unknown_fields(&document);
+    let mut interpolation_errors = Vec::new();
+    for (key, value) in document.iter_mut() {
+        if let Err(reason) = context.interpolate_value(value, key) {
+            interpolation_errors.push(reason);
+        }
+    }
+    let locate = |path: &[validate::Segment], reason: String| match validate::locate(
+        &files,
+        &document,
+        environment,
+        path,
+    ) {
+        Some(location) => format!("{} ({})", reason, location),
+        None => reason,
+    };
+    let mut errors: Vec<String> = unknown
+        .iter()
+        .map(|path| {
+            locate(
+                path,
+                format!("unknown field '{}'", validate::format_path(path)),
+            )
+        })
+        .collect();
+    errors.extend(interpolation_errors);
+
+    let mut config: Config = match validate::deserialize(toml::Value::Table(document.clone()), &[])
+    {
+        Ok(config) => config,
+        Err(_) => {
+            for (path, reason) in validate::type_errors(&document) {
+                let reason = if path.is_empty() {
+                    format!("invalid config: {}", reason)
+                } else {
+                    format!("{}: {}", validate::format_path(&path), reason)
+                };
+                errors.push(locate(&path, reason));
+            }
+            return Err(failed(None, errors));
+        }
+    };
+    config.secret_values = secret_values.clone();
+    let verify = match &mut config.canary {
+        Some(CanarySpec::Full(canary)) => canary.verify.as_mut_slice(),
+        _ => &mut [],
+    };
+    for action in config.actions.iter_mut().chain(verify) {
+        if let Err(reason) = render_action_templates(action, &context) {
+            errors.push(reason);
+        }
+    }
+    if let Err(inventory_errors) = config.resolve_inventory() {
+        errors.extend(inventory_errors);
+    }
+    if !errors.is_empty() {
+        return Err(failed(Some(Box::new(config)), errors));
+    }
+
+    Ok(config)
+}
+
+// Parses durations such as "30s", "5m", "1h30m" or "500ms", a bare number
+// is a number of seconds
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Ok(Duration::from_secs(seconds));
+    }
+
+    let invalid = || {
+        format!(
+            "invalid duration '{}', expected e.g. \"30s\" or \"1m30s\"",
+            value
+        )
+    };
+    if value.is_empty() {
+        return Err(invalid());
+    }
+    let mut total = Duration::ZERO;
+    let mut rest = value;
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let amount: u64 = rest[..digits].parse().map_err(|_| invalid())?;
+        rest = &rest[digits..];
+        let unit = rest
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        total += match &rest[..unit] {
+            "ms" => Duration::from_millis(amount),
+            "s" => Duration::from_secs(amount),
+            "m" => Duration::from_secs(amount * 60),
+            "h" => Duration::from_secs(amount * 3600),
+            _ => return Err(invalid()),
+        };
+        rest = &rest[unit..];
+    }
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_hostnames_and_ip_addresses() {
+        let address = parse_address("example.com").unwrap();
+        assert_eq!(address.host, "example.com");
+        assert_eq!(address.user, None);
+        assert_eq!(address.port, None);
+
+        let address = parse_address("146.59.159.230").unwrap();
+        assert_eq!(address.host, "146.59.159.230");
+
+        // IPv6 without brackets cannot carry a port
+        let address = parse_address("fe80::1").unwrap();
+        assert_eq!(address.host, "fe80::1");
+        assert_eq!(address.port, None);
+    }
+
+    #[test]
+    fn parses_users_and_ports() {
+        let address = parse_address("ssh://deploy@example.com:2222/").unwrap();
+        assert_eq!(address.user.as_deref(), Some("deploy"));
+        assert_eq!(address.host, "example.com");
+        assert_eq!(address.port, Some(2222));
+
+        let address = parse_address("ubuntu@10.0.0.1:22").unwrap();
+        assert_eq!(address.user.as_deref(), Some("ubuntu"));
+        assert_eq!(address.host, "10.0.0.1");
+        assert_eq!(address.port, Some(22));
+
+        let address = parse_address("[::1]:2222").unwrap();
+        assert_eq!(address.host, "::1");
+        assert_eq!(address.port, Some(2222));
+
+        let address = parse_address("ssh://root@[2001:db8::2]").unwrap();
+        assert_eq!(address.user.as_deref(), Some("root"));
+        assert_eq!(address.host, "2001:db8::2");
+        assert_eq!(address.port, None);
+    }
+
+    #[test]
+    fn rejects_invalid_addresses() {
+        for value in [
+            "",
+            "http://example.com",
+            "@example.com",
+            "ssh://u@h:0",
+            "example.com:70000",
+            "example.com:ssh",
+            "[::1",
+            "[::1]2222",
+            "user@:22",
+        ] {
+            assert!(parse_address(value).is_err(), "'{}' was accepted", value);
+        }
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("30"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_duration("30s"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_duration(" 5m "), Ok(Duration::from_secs(300)));
+        assert_eq!(parse_duration("2h"), Ok(Duration::from_secs(7200)));
+        assert_eq!(parse_duration("1h30m"), Ok(Duration::from_secs(5400)));
+        assert_eq!(parse_duration("1m30s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("500ms"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse_duration("1s500ms"), Ok(Duration::from_millis(1500)));
+    }
+
+    #[test]
+    fn rejects_invalid_durations() {
+        for value in ["", "s", "10x", "1.5s", "-1s", "1h30", "5 m", "m5"] {
+            assert!(parse_duration(value).is_err(), "'{}' was accepted", value);
+        }
+    }
+
+    #[test]
+    fn merges_actions_by_name() {
+        let mut base: toml::Value = toml::from_str(
+            r#"
+            [server]
+            host = "staging.example.com"
+            user = "ubuntu"
+
+            [[actions]]
+            type = "commands"
+            name = "start"
+            commands = ["docker-compose up -d"]
+            hosts = "api"
+            "#,
+        )
+        .unwrap();
+        let overlay = toml::from_str(
+            r#"
+            [server]
+            host = "prod.example.com"
+
+            [[actions]]
+            name = "start"
+            commands = ["docker-compose -f prod.yml up -d"]
+
+            [[actions]]
+            type = "commands"
+            name = "check"
+            commands = ["curl -f localhost"]
+            "#,
+        )
+        .unwrap();
+        merge(&mut base, overlay, "");
+
+        assert_eq!(base["server"]["host"].as_str(), Some("prod.example.com"));
+        assert_eq!(base["server"]["user"].as_str(), Some("ubuntu"));
+        let actions = base["actions"].as_array().unwrap();
+        assert_eq!(actions.len(), 2);
+        // other arrays are replaced, not merged
+        assert_eq!(
+            actions[0]["commands"].as_array().unwrap(),
+            &[toml::Value::from("docker-compose -f prod.yml up -d")]
+        );
+        assert_eq!(actions[0]["hosts"].as_str(), Some("api"));
+        assert_eq!(actions[1]["name"].as_str(), Some("check"));
+    }
+
+    // Writes the files in a new temporary folder
+    fn write_files(test: &str, files: &[(&str, &str)]) -> PathBuf {
+        let folder =
+            std::env::temp_dir().join(format!("deploy-config-{}-{}", test, std::process::id()));
+        fs::create_dir_all(&folder).unwrap();
+        for (name, contents) in files {
+            fs::write(folder.join(name), contents).unwrap();
+        }
+        folder
+    }
+
+    #[test]
+    fn detects_include_cycles() {
+        let folder = write_files(
+            "cycle",
+            &[
+                ("app.deploy.toml", "extends = \"base.toml\"\n"),
+                (
+                    "base.toml",
+                    "[[actions]]\ntype = \"include\"\nfile = \"app.deploy.toml\"\n",
+                ),
+            ],
+        );
+        let path = folder.join("app.deploy.toml");
+        let error = read_document(&path, &mut Vec::new(), &mut Vec::new()).unwrap_err();
+        fs::remove_dir_all(&folder).unwrap();
+        assert!(error.starts_with("include cycle: "), "{}", error);
+        assert_eq!(error.matches("app.deploy.toml").count(), 2, "{}", error);
+    }
+
+    #[test]
+    fn expands_includes_of_environments() {
+        let include = "[[actions]]\ntype = \"include\"\nfile = \"checks.toml\"\n";
+        let folder = write_files(
+            "environments",
+            &[
+                (
+                    "app.deploy.toml",
+                    "[[actions]]\ntype = \"commands\"\nname = \"start\"\ncommands = [\"up\"]\n\n\
+                     [[env.staging.actions]]\ntype = \"include\"\nfile = \"checks.toml\"\n",
+                ),
+                ("app.prod.deploy.toml", include),
+                (
+                    "checks.toml",
+                    "[[actions]]\ntype = \"commands\"\nname = \"check\"\ncommands = [\"curl\"]\n",
+                ),
+            ],
+        );
+        let path = folder.join("app.deploy.toml");
+        for environment in ["staging", "prod"] {
+            let mut files = Vec::new();
+            let mut document = read_document(&path, &mut Vec::new(), &mut files).unwrap();
+            apply_environment(
+                &mut document,
+                path.to_str().unwrap(),
+                Some(environment),
+                &mut files,
+            )
+            .unwrap();
+            let names: Vec<&str> = document["actions"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|action| action["name"].as_str().unwrap())
+                .collect();
+            assert_eq!(names, ["start", "check"], "in {}", environment);
+        }
+        fs::remove_dir_all(&folder).unwrap();
+    }
 }
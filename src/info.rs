@@ -1,4 +1,5 @@
 use std::{
+    collections::HashSet,
     fs::File,
     io::{self, BufRead},
     path::{Path, PathBuf},
@@ -25,11 +26,16 @@ pub fn get_info() {
             let conf_path = Path::new(&conf_name);
             let conf_file_name = &conf_path.file_name().unwrap_or_default().to_string_lossy();
             let conf_parent = conf_path.parent().unwrap().to_string_lossy();
+            let environment = match extract_environment(&file_path) {
+                Ok(Some(environment)) => format!(" ({})", environment.yellow()),
+                _ => String::new(),
+            };
             println!(
-                "{}) {}/{}, {}",
+                "{}) {}/{}{}, {}",
                 i,
                 conf_parent.bright_black(),
                 conf_file_name.bright_green(),
+                environment,
                 date.with_timezone(&Local).format("%d-%m-%Y %H:%M:%S")
             );
             match &repo_opt {
@@ -42,6 +48,15 @@ pub fn get_info() {
                 },
                 Err(_) => {}
             }
+            if let Ok(batches) = extract_batches(&file_path) {
+                for batch in batches {
+                    if batch.contains(" aborted:") {
+                        println!("   {}", batch.bright_red());
+                    } else {
+                        println!("   {}", batch.bright_black());
+                    }
+                }
+            }
 
             i += 1;
         } else {
@@ -94,3 +109,99 @@ fn extract_info(path: &PathBuf) -> io::Result<(String, DateTime<Utc>, String)> {
 
     Ok((config_name, date, commit_hash))
 }
+
+// Environment given with --env, if any
+fn extract_environment(path: &Path) -> io::Result<Option<String>> {
+    let file = File::open(path)?;
+    Ok(io::BufReader::new(file)
+        .lines()
+        .map_while(Result::ok)
+        .find_map(|line| {
+            line.strip_prefix("Environment: ")
+                .map(|s| s.trim().to_string())
+        }))
+}
+
+// Outcome of each batch of a rolling deployment
+fn extract_batches(path: &Path) -> io::Result<Vec<String>> {
+    let file = File::open(path)?;
+    Ok(io::BufReader::new(file)
+        .lines()
+        .map_while(Result::ok)
+        .filter(|line| {
+            line.starts_with("Batch ") && (line.contains(" finished:") || line.contains(" aborted:"))
+        })
+        .collect())
+}
+
+// Config file of a deployment, from its first line
+fn extract_config_name(path: &Path) -> io::Result<String> {
+    let file = File::open(path)?;
+    io::BufReader::new(file)
+        .lines()
+        .next()
+        .and_then(|line| line.ok())
+        .and_then(|line| line.strip_prefix("Loading: ").map(|s| s.trim().to_string()))
+        .ok_or(io::Error::new(
+            io::ErrorKind::Other,
+            "Config name not found",
+        ))
+}
+
+// Most recent deployment log of the config in the environment, None
+// matching deployments without --env
+pub fn last_deployment(config_path: &str, environment: Option<&str>) -> Option<PathBuf> {
+    let folder_path = Path::new(".deployments");
+    let canonical = Path::new(config_path).canonicalize().ok();
+    let mut last = None;
+    let mut i = 1;
+    loop {
+        let file_path = folder_path.join(format!("deployment_{}.txt", i));
+        if !file_path.exists() {
+            return last;
+        }
+        if let Ok(conf_name) = extract_config_name(&file_path) {
+            let same_config = conf_name == config_path
+                || (canonical.is_some() && Path::new(&conf_name).canonicalize().ok() == canonical);
+            if same_config
+                && extract_environment(&file_path).ok().flatten().as_deref() == environment
+            {
+                last = Some(file_path);
+            }
+        }
+        i += 1;
+    }
+}
+
+// (host, action) pairs started during a deployment in order, and those that
+// finished
+type ActionRuns = (Vec<(String, String)>, HashSet<(String, String)>);
+
+// Actions of a deployment log, read back by --resume
+pub fn extract_actions(path: &Path) -> io::Result<ActionRuns> {
+    let file = File::open(path)?;
+    let mut started = Vec::new();
+    let mut finished = HashSet::new();
+    // lines of hosts deployed one at a time follow their "Host:" line, those
+    // deployed in parallel are prefixed with "[host] "
+    let mut current_host = String::new();
+    for line in io::BufReader::new(file).lines().map_while(Result::ok) {
+        let (host, message) = match line
+            .strip_prefix('[')
+            .and_then(|rest| rest.split_once("] "))
+        {
+            Some((host, message)) if host != "stderr" && !host.starts_with('+') => {
+                (host.to_string(), message)
+            }
+            _ => (current_host.clone(), line.as_str()),
+        };
+        if let Some(name) = message.strip_prefix("Host: ") {
+            current_host = name.trim().to_string();
+        } else if let Some(name) = message.strip_prefix("Action: ") {
+            started.push((host, name.trim().to_string()));
+        } else if let Some(name) = message.strip_prefix("Finished: ") {
+            finished.insert((host, name.trim().to_string()));
+        }
+    }
+    Ok((started, finished))
+}
@@ -0,0 +1,228 @@
+use std::collections::HashMap;
+use std::env;
+
+use toml::Value;
+
+// Names of the ${...} placeholders of the string, skipping "$${" escapes
+pub fn references(value: &str) -> Vec<String> {
+    let mut names = Vec::new();
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        if rest[..start].ends_with('$') {
+            rest = &rest[start + 2..];
+            continue;
+        }
+        let Some(end) = rest[start..].find('}') else {
+            break;
+        };
+        names.push(rest[start + 2..start + end].trim().to_string());
+        rest = &rest[start + end + 1..];
+    }
+    names
+}
+
+// HEAD of the repository the deployment is started from
+#[derive(Default)]
+pub struct Git {
+    pub commit: Option<String>,
+    pub branch: Option<String>,
+}
+
+// Values available to ${...} placeholders
+pub struct Context {
+    vars: HashMap<String, String>,
+    secrets: HashMap<String, String>,
+    git: Git,
+}
+
+impl Context {
+    pub fn new(git: Git) -> Context {
+        Context {
+            vars: HashMap::new(),
+            secrets: HashMap::new(),
+            git,
+        }
+    }
+
+    pub fn set(&mut self, name: &str, value: &str) {
+        self.vars.insert(name.to_string(), value.to_string());
+    }
+
+    pub fn set_secret(&mut self, name: &str, value: &str) {
+        self.secrets.insert(name.to_string(), value.to_string());
+    }
+
+    // None for names that are not deploy variables, left as is since they
+    // are likely shell variables
+    fn lookup(&self, name: &str) -> Result<Option<String>, String> {
+        if let Some(var) = name.strip_prefix("env.") {
+            return env::var(var)
+                .map(Some)
+                .map_err(|_| format!("environment variable '{}' is not set", var));
+        }
+        if let Some(secret) = name.strip_prefix("secret.") {
+            return self
+                .secrets
+                .get(secret)
+                .cloned()
+                .map(Some)
+                .ok_or_else(|| format!("secret '{}' is not defined in [secrets]", secret));
+        }
+        if let Some(field) = name.strip_prefix("git.") {
+            let value = match field {
+                "commit" => &self.git.commit,
+                "branch" => &self.git.branch,
+                _ => {
+                    return Err(format!(
+                        "unknown '${{{}}}', expected git.commit or git.branch",
+                        name
+                    ))
+                }
+            };
+            return value
+                .clone()
+                .map(Some)
+                .ok_or_else(|| format!("'${{{}}}' is not available outside a git branch", name));
+        }
+        Ok(self.vars.get(name).cloned())
+    }
+
+    // Replaces the ${...} placeholders of the string, "$${" gives a literal "${"
+    pub fn interpolate(&self, value: &str) -> Result<String, String> {
+        let mut result = String::new();
+        let mut rest = value;
+        while let Some(start) = rest.find("${") {
+            if rest[..start].ends_with('$') {
+                result.push_str(&rest[..start]);
+                result.push('{');
+                rest = &rest[start + 2..];
+                continue;
+            }
+            result.push_str(&rest[..start]);
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| format!("unclosed '${{' in '{}'", value))?;
+            let name = rest[start + 2..start + end].trim();
+            match self.lookup(name)? {
+                Some(replacement) => result.push_str(&replacement),
+                None => result.push_str(&rest[start..=start + end]),
+            }
+            rest = &rest[start + end + 1..];
+        }
+        result.push_str(rest);
+        Ok(result)
+    }
+
+    // Interpolates every string of the value, `path` locates errors
+    pub fn interpolate_value(&self, value: &mut Value, path: &str) -> Result<(), String> {
+        match value {
+            Value::String(string) => {
+                *string = self
+                    .interpolate(string)
+                    .map_err(|reason| format!("{}: {}", path, reason))?;
+            }
+            Value::Array(values) => {
+                for (index, value) in values.iter_mut().enumerate() {
+                    self.interpolate_value(value, &format!("{}[{}]", path, index))?;
+                }
+            }
+            Value::Table(table) => {
+                for (key, value) in table.iter_mut() {
+                    let path = match path {
+                        "" => key.clone(),
+                        _ => format!("{}.{}", path, key),
+                    };
+                    self.interpolate_value(value, &path)?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context() -> Context {
+        let mut context = Context::new(Git {
+            commit: Some("abc123".to_string()),
+            branch: None,
+        });
+        context.set("app_dir", "~/app");
+        context.set_secret("TOKEN", "s3cret");
+        context
+    }
+
+    #[test]
+    fn replaces_placeholders() {
+        let context = context();
+        assert_eq!(
+            context
+                .interpolate("cd ${app_dir} && ${ app_dir }/run")
+                .unwrap(),
+            "cd ~/app && ~/app/run"
+        );
+        assert_eq!(
+            context.interpolate("TOKEN=${secret.TOKEN}").unwrap(),
+            "TOKEN=s3cret"
+        );
+        assert_eq!(
+            context.interpolate("api:${git.commit}").unwrap(),
+            "api:abc123"
+        );
+        env::set_var("DEPLOY_INTERPOLATE_TEST", "value");
+        assert_eq!(
+            context
+                .interpolate("${env.DEPLOY_INTERPOLATE_TEST}")
+                .unwrap(),
+            "value"
+        );
+    }
+
+    #[test]
+    fn leaves_shell_variables_and_escapes() {
+        let context = context();
+        assert_eq!(context.interpolate("echo ${HOME}").unwrap(), "echo ${HOME}");
+        assert_eq!(
+            context.interpolate("echo $${app_dir}").unwrap(),
+            "echo ${app_dir}"
+        );
+        assert_eq!(context.interpolate("cost: $5 {x}").unwrap(), "cost: $5 {x}");
+    }
+
+    #[test]
+    fn rejects_unknown_values() {
+        let context = context();
+        for value in [
+            "${app_dir",
+            "${secret.MISSING}",
+            "${git.branch}",
+            "${git.tag}",
+            "${env.DEPLOY_INTERPOLATE_UNSET}",
+        ] {
+            assert!(
+                context.interpolate(value).is_err(),
+                "'{}' was accepted",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn locates_errors_in_values() {
+        let context = context();
+        let mut value: Value = toml::from_str("commands = ['ok', '${secret.MISSING}']").unwrap();
+        let error = context.interpolate_value(&mut value, "").unwrap_err();
+        assert!(error.starts_with("commands[1]: "), "{}", error);
+    }
+
+    #[test]
+    fn lists_references() {
+        assert_eq!(
+            references("${a}/${ env.HOME }/$${b}/${c"),
+            ["a", "env.HOME"]
+        );
+    }
+}
@@ -10,14 +10,14 @@ use crossterm::{
 use futures::{future::FutureExt, StreamExt};
 use lazy_static::lazy_static;
 use regex::Regex;
-use russh::{client, Channel, ChannelMsg};
-use std::str;
+use russh::{client, Channel, ChannelMsg, Sig};
 use std::{
     collections::VecDeque,
     fs::{self, OpenOptions},
     io::{stdout, Write},
     path::Path,
     sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
 pub const REMOTE_TERM_SIZE: usize = 5;
@@ -25,9 +25,122 @@ pub const REMOTE_TERM_SIZE: usize = 5;
 lazy_static! {
     pub static ref ANSI_ESCAPE_CODE: Regex = Regex::new("\x1B\\[[0-9;]*[a-zA-Z]").unwrap();
 }
+
+// Accumulates remote output until complete lines are available, a chunk
+// can end in the middle of a line (or of a UTF-8 character)
+#[derive(Default)]
+struct LineBuffer {
+    partial: Vec<u8>,
+}
+
+impl LineBuffer {
+    fn push(&mut self, data: &[u8]) -> Vec<String> {
+        self.partial.extend_from_slice(data);
+        let mut lines = Vec::new();
+        while let Some(pos) = self.partial.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.partial.drain(..=pos).collect();
+            lines.push(String::from_utf8_lossy(&line).trim_end().to_string());
+        }
+        lines
+    }
+
+    fn flush(&mut self) -> Option<String> {
+        if self.partial.is_empty() {
+            return None;
+        }
+        let line = String::from_utf8_lossy(&self.partial)
+            .trim_end()
+            .to_string();
+        self.partial.clear();
+        Some(line)
+    }
+}
+
+// How start_remote_logging follows a command, besides waiting for it to exit
+#[derive(Default)]
+pub struct RemoteWatch {
+    // stop following once a line of output matches
+    pub detach_on: Option<Regex>,
+    // stop following after this long
+    pub detach_after: Option<Duration>,
+    // don't send SIGTERM to the command when detaching automatically
+    pub keep_running: bool,
+    // fail as soon as a line of output matches one of these
+    pub fail_on: Vec<Regex>,
+    // fail if no line of output matched by the end of the command
+    pub expect_output: Option<Regex>,
+}
+
+pub enum RemoteExit {
+    Status(u32),
+    // killed by a signal, with its name
+    Signal(String),
+    // stopped following the command, it may still be running
+    Detached,
+    // the channel closed without an exit status
+    Closed,
+    // the output did not pass the watch assertions
+    Failed(String),
+}
+
+enum Trigger {
+    Detach(String),
+    Fail(String),
+}
+
+async fn wait_until(deadline: Option<tokio::time::Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => futures::future::pending().await,
+    }
+}
+
+// Terminal events, never resolves when there is no terminal to read from
+async fn next_event(reader: &mut Option<EventStream>) -> Option<std::io::Result<Event>> {
+    match reader {
+        Some(reader) => reader.next().await,
+        None => futures::future::pending().await,
+    }
+}
+
+// Replaces the "Remote console" status line with a line of remote output,
+// stderr is printed in red
+fn print_remote_line(line: &str, is_stderr: bool) {
+    execute!(
+        stdout(),
+        MoveUp(1),
+        Clear(ClearType::CurrentLine),
+        SetForegroundColor(Color::DarkGrey),
+        Print("$ "),
+        SetForegroundColor(if is_stderr { Color::Red } else { Color::Reset }),
+        Print(line),
+        SetForegroundColor(Color::Reset),
+        Print("\n"),
+        MoveToColumn(0),
+        Clear(ClearType::CurrentLine),
+        SetForegroundColor(Color::DarkGrey),
+        Print("Remote console: "),
+        SetForegroundColor(Color::Reset),
+        Print("Press ESC to quit"),
+        Print("\n"),
+        MoveToColumn(0),
+    )
+    .unwrap();
+}
+
 pub struct Logger {
-    log_file: Arc<tokio::sync::Mutex<std::fs::File>>,
+    // None when nothing is deployed, such as for --plan
+    log_file: Option<Arc<tokio::sync::Mutex<std::fs::File>>>,
     remote_buffer: Arc<Mutex<VecDeque<String>>>,
+    start_time: Instant,
+    // prefix remote output in the log file with the time since start
+    timestamps: bool,
+    // append lines only, for CI and redirected output
+    plain: bool,
+    // host name prepended to every line when deploying hosts in parallel
+    prefix: Option<String>,
+    // values replaced by **** before anything is printed or saved
+    secrets: Arc<Vec<String>>,
 }
 
 impl Logger {
@@ -51,17 +164,90 @@ impl Logger {
                     .open(file_path)
                     .expect("cannot open file");
 
-                return Logger {
-                    log_file: Arc::new(tokio::sync::Mutex::new(log_file)),
-                    remote_buffer: Arc::new(Mutex::new(VecDeque::new())),
-                };
+                let mut logger = Logger::without_file();
+                logger.log_file = Some(Arc::new(tokio::sync::Mutex::new(log_file)));
+                return logger;
             }
             num += 1;
         }
     }
 
+    // Logger printing to the terminal only
+    pub fn without_file() -> Logger {
+        Logger {
+            log_file: None,
+            remote_buffer: Arc::new(Mutex::new(VecDeque::new())),
+            start_time: Instant::now(),
+            timestamps: false,
+            plain: false,
+            prefix: None,
+            secrets: Arc::new(Vec::new()),
+        }
+    }
+
+    // Logger writing to the same file, for a host deployed alongside others.
+    // Lines are prefixed with the host name and printed in plain mode
+    pub fn for_host(&self, host_name: &str) -> Logger {
+        Logger {
+            log_file: self.log_file.clone(),
+            remote_buffer: Arc::new(Mutex::new(VecDeque::new())),
+            start_time: self.start_time,
+            timestamps: self.timestamps,
+            plain: true,
+            prefix: Some(host_name.to_string()),
+            secrets: self.secrets.clone(),
+        }
+    }
+
+    pub fn set_timestamps(&mut self, timestamps: bool) {
+        self.timestamps = timestamps;
+    }
+
+    pub fn set_plain(&mut self, plain: bool) {
+        self.plain = plain;
+    }
+
+    pub fn is_plain(&self) -> bool {
+        self.plain
+    }
+
+    pub fn set_secrets(&mut self, secrets: &[String]) {
+        let mut secrets: Vec<String> = secrets
+            .iter()
+            .filter(|secret| !secret.is_empty())
+            .cloned()
+            .collect();
+        // longest first, in case a secret contains another one
+        secrets.sort_by_key(|secret| std::cmp::Reverse(secret.len()));
+        self.secrets = Arc::new(secrets);
+    }
+
+    fn redact(&self, message: &str) -> String {
+        let mut message = message.to_string();
+        for secret in self.secrets.iter() {
+            message = message.replace(secret.as_str(), "****");
+        }
+        message
+    }
+
+    // Prints a line to the terminal only
+    pub fn print(&self, message: &str) {
+        let message = self.redact(message);
+        match &self.prefix {
+            Some(prefix) => println!("{} {}", format!("[{}]", prefix).cyan(), message),
+            None => println!("{}", message),
+        }
+    }
+
     async fn log_to_file(&mut self, message: String) {
-        let mut log_file = self.log_file.lock().await;
+        let Some(log_file) = &self.log_file else {
+            return;
+        };
+        let message = match &self.prefix {
+            Some(prefix) => format!("[{}] {}", prefix, self.redact(&message)),
+            None => self.redact(&message),
+        };
+        let mut log_file = log_file.lock().await;
         if let Err(e) = writeln!(log_file, "{}", message) {
             eprintln!("Failed to write to log file: {}", e);
         }
@@ -71,12 +257,90 @@ impl Logger {
     }
 
     pub async fn log(&mut self, message: String) {
-        println!("{}", message);
+        self.print(&message);
         self.log_to_file(ANSI_ESCAPE_CODE.replace_all(&message, "").to_string())
             .await;
     }
 
+    // Prints and saves a line of remote output, lines matching a fail_on
+    // pattern are highlighted
+    async fn remote_line(&mut self, line: &str, is_stderr: bool, failing: Option<&Regex>) {
+        let highlighted = failing.map(|_| line.white().on_red().to_string());
+        let shown = highlighted.as_deref().unwrap_or(line);
+        if self.plain {
+            if is_stderr && failing.is_none() {
+                self.print(&format!("{} {}", "$".bright_black(), shown.red()));
+            } else {
+                self.print(&format!("{} {}", "$".bright_black(), shown));
+            }
+        } else {
+            print_remote_line(&self.redact(shown), is_stderr);
+        }
+
+        let mut entry = format!(
+            "{} {}",
+            if is_stderr { "[stderr]" } else { "$" },
+            ANSI_ESCAPE_CODE.replace_all(line, "")
+        );
+        if let Some(pattern) = failing {
+            entry = format!("{}  <-- matches fail_on '{}'", entry, pattern);
+        }
+        if self.timestamps {
+            entry = format!(
+                "[+{:.3}s] {}",
+                self.start_time.elapsed().as_secs_f64(),
+                entry
+            );
+        }
+        self.log_to_file(entry).await;
+    }
+
+    // Runs a line of output through the watch, expected_seen is set once
+    // expect_output matched
+    async fn watch_line(
+        &mut self,
+        line: &str,
+        is_stderr: bool,
+        watch: &RemoteWatch,
+        expected_seen: &mut bool,
+    ) -> Option<Trigger> {
+        let stripped = ANSI_ESCAPE_CODE.replace_all(line, "");
+        let failing = watch
+            .fail_on
+            .iter()
+            .find(|pattern| pattern.is_match(&stripped));
+        self.remote_line(line, is_stderr, failing).await;
+
+        if let Some(expected) = &watch.expect_output {
+            if expected.is_match(&stripped) {
+                *expected_seen = true;
+            }
+        }
+        if let Some(pattern) = failing {
+            return Some(Trigger::Fail(format!(
+                "output matched fail_on '{}'",
+                pattern
+            )));
+        }
+        match &watch.detach_on {
+            Some(pattern) if pattern.is_match(&stripped) => {
+                Some(Trigger::Detach(format!("output matched '{}'", pattern)))
+            }
+            _ => None,
+        }
+    }
+
     pub async fn add_uploaded_file(&mut self, file_name: String) {
+        if self.plain {
+            self.print(&format!(
+                "{} '{}'",
+                "✔".bright_green(),
+                file_name.bright_black()
+            ));
+            self.log_to_file(format!("✔ '{}'", file_name)).await;
+            return;
+        }
+
         let mut buffer = self.remote_buffer.lock().unwrap();
         let prev_buffer_length: u16 = buffer.len().try_into().unwrap();
 
@@ -89,12 +353,10 @@ impl Logger {
             file_name.bright_black()
         ));
         // saving without colors and no flushing
-        if let Err(e) = writeln!(
-            self.log_file.lock().await,
-            "{}",
-            format!("✔ '{}'", file_name)
-        ) {
-            eprintln!("Failed to write to log file: {}", e);
+        if let Some(log_file) = &self.log_file {
+            if let Err(e) = writeln!(log_file.lock().await, "✔ '{}'", file_name) {
+                eprintln!("Failed to write to log file: {}", e);
+            }
         }
 
         let mut writer = stdout();
@@ -115,97 +377,208 @@ impl Logger {
             .unwrap();
     }
 
-    pub async fn stop_files_display(&mut self) {
-        self.remote_buffer = Arc::new(Mutex::new(VecDeque::new()));
-        if let Err(e) = self.log_file.lock().await.flush() {
+    async fn flush_log_file(&self) {
+        let Some(log_file) = &self.log_file else {
+            return;
+        };
+        if let Err(e) = log_file.lock().await.flush() {
             eprintln!("Failed to flush log file: {}", e);
         }
     }
 
-    pub async fn start_remote_logging(&mut self, mut channel: Channel<client::Msg>) {
-        execute!(
-            stdout(),
-            Clear(ClearType::CurrentLine),
-            SetForegroundColor(Color::DarkGrey),
-            Print("Remote console: "),
-            SetForegroundColor(Color::Reset),
-            Print("loading"),
-            Print("\n"),
-            MoveToColumn(0),
-        )
-        .unwrap();
+    pub async fn stop_files_display(&mut self) {
+        self.remote_buffer = Arc::new(Mutex::new(VecDeque::new()));
+        self.flush_log_file().await;
+    }
+
+    // Streams the remote output until the command exits, ESC is pressed (not
+    // available in plain mode) or the watch detaches from it
+    pub async fn start_remote_logging(
+        &mut self,
+        mut channel: Channel<client::Msg>,
+        watch: &RemoteWatch,
+    ) -> RemoteExit {
+        let mut reader = None;
+        if !self.plain {
+            execute!(
+                stdout(),
+                Clear(ClearType::CurrentLine),
+                SetForegroundColor(Color::DarkGrey),
+                Print("Remote console: "),
+                SetForegroundColor(Color::Reset),
+                Print("loading"),
+                Print("\n"),
+                MoveToColumn(0),
+            )
+            .unwrap();
+
+            enable_raw_mode().unwrap();
+            reader = Some(EventStream::new());
+        }
 
-        enable_raw_mode().unwrap();
-        let mut reader = EventStream::new();
+        let deadline = watch
+            .detach_after
+            .map(|duration| tokio::time::Instant::now() + duration);
+        let mut exit = RemoteExit::Closed;
+        let mut trigger = None;
+        let mut expected_seen = watch.expect_output.is_none();
+        let mut stdout_lines = LineBuffer::default();
+        let mut stderr_lines = LineBuffer::default();
         loop {
-            tokio::select! {
-                msg = reader.next().fuse() => match msg {
+            let lines: Vec<(String, bool)> = tokio::select! {
+                msg = next_event(&mut reader).fuse() => match msg {
                     Some(Ok(event)) => {
                         if event == Event::Key(KeyCode::Esc.into()) {
-                            execute!(
-                                stdout(),
-                                MoveUp(1),
-                                Clear(ClearType::CurrentLine),
-                                SetForegroundColor(Color::Green),
-                                Print("Remote console: "),
-                                SetForegroundColor(Color::Reset),
-                                Print("finished\n"),
-                                MoveToColumn(0),
-                            )
-                            .unwrap();
+                            exit = RemoteExit::Detached;
                             break;
                         }
+                        continue;
                     },
-                    None => break,
-                    _ => {},
+                    // no more terminal events, stop following like ESC
+                    None => {
+                        exit = RemoteExit::Detached;
+                        break;
+                    },
+                    _ => continue,
+                },
+                _ = wait_until(deadline) => {
+                    trigger = Some(Trigger::Detach("detach_after elapsed".to_string()));
+                    break;
                 },
                 channel_msg = channel.wait() => match channel_msg {
-                    Some(next_msg) => {
-                        match next_msg {
-                            ChannelMsg::Data { ref data } => {
-                                let bytes = data.as_ref();
-                                let next_line = (str::from_utf8(bytes).expect("Invalid UTF-8")).trim_end();
-                                execute!(
-                                    stdout(),
-                                    MoveUp(1),
-                                    Clear(ClearType::CurrentLine),
-                                    SetForegroundColor(Color::DarkGrey),
-                                    Print("$ "),
-                                    SetForegroundColor(Color::Reset),
-                                    Print(next_line),
-                                    Print("\n"),
-                                    MoveToColumn(0),
-                                    Clear(ClearType::CurrentLine),
-                                    SetForegroundColor(Color::DarkGrey),
-                                    Print("Remote console: "),
-                                    SetForegroundColor(Color::Reset),
-                                    Print("Press ESC to quit"),
-                                    Print("\n"),
-                                    MoveToColumn(0),
-                                )
-                                .unwrap();
-                            }
-                            ChannelMsg::ExitStatus { exit_status : _ } => {
-                                // println!("Exit status: {}", exit_status);
-                                break;
-                            }
-                            _ => {}
-                        }
+                    Some(ChannelMsg::Data { ref data }) => stdout_lines
+                        .push(data)
+                        .into_iter()
+                        .map(|line| (line, false))
+                        .collect(),
+                    Some(ChannelMsg::ExtendedData { ref data, ext: 1 }) => stderr_lines
+                        .push(data)
+                        .into_iter()
+                        .map(|line| (line, true))
+                        .collect(),
+                    Some(ChannelMsg::ExitStatus { exit_status }) => {
+                        exit = RemoteExit::Status(exit_status);
+                        break;
+                    },
+                    Some(ChannelMsg::ExitSignal { signal_name, .. }) => {
+                        exit = RemoteExit::Signal(format!("{:?}", signal_name));
+                        break;
                     },
+                    Some(_) => continue,
                     None => break,
                 },
+            };
+
+            // every line of the chunk is logged before acting on a trigger
+            for (line, is_stderr) in lines {
+                let triggered = self
+                    .watch_line(&line, is_stderr, watch, &mut expected_seen)
+                    .await;
+                if trigger.is_none() {
+                    trigger = triggered;
+                }
+            }
+            if trigger.is_some() {
+                break;
             }
         }
 
-        disable_raw_mode().unwrap();
+        // output already received when failing is logged as well
+        if matches!(trigger, Some(Trigger::Fail(_))) {
+            while let Ok(Some(msg)) = tokio::time::timeout(Duration::ZERO, channel.wait()).await {
+                let lines: Vec<(String, bool)> = match msg {
+                    ChannelMsg::Data { ref data } => stdout_lines
+                        .push(data)
+                        .into_iter()
+                        .map(|line| (line, false))
+                        .collect(),
+                    ChannelMsg::ExtendedData { ref data, ext: 1 } => stderr_lines
+                        .push(data)
+                        .into_iter()
+                        .map(|line| (line, true))
+                        .collect(),
+                    ChannelMsg::ExitStatus { exit_status } => {
+                        exit = RemoteExit::Status(exit_status);
+                        continue;
+                    }
+                    _ => continue,
+                };
+                for (line, is_stderr) in lines {
+                    self.watch_line(&line, is_stderr, watch, &mut expected_seen)
+                        .await;
+                }
+            }
+        }
 
-        // Ensure writing logs to file
-        if let Err(e) = self.log_file.lock().await.flush() {
-            eprintln!("Failed to flush log file: {}", e);
+        if !self.plain {
+            if trigger.is_some() || matches!(exit, RemoteExit::Detached) {
+                execute!(
+                    stdout(),
+                    MoveUp(1),
+                    Clear(ClearType::CurrentLine),
+                    SetForegroundColor(Color::Green),
+                    Print("Remote console: "),
+                    SetForegroundColor(Color::Reset),
+                    Print("finished\n"),
+                    MoveToColumn(0),
+                )
+                .unwrap();
+            }
+            disable_raw_mode().unwrap();
+        }
+
+        for (line, is_stderr) in [(stdout_lines.flush(), false), (stderr_lines.flush(), true)] {
+            if let Some(line) = line {
+                if let Some(Trigger::Fail(reason)) = self
+                    .watch_line(&line, is_stderr, watch, &mut expected_seen)
+                    .await
+                {
+                    trigger.get_or_insert(Trigger::Fail(reason));
+                }
+            }
+        }
+
+        match trigger {
+            Some(Trigger::Detach(reason)) => {
+                exit = RemoteExit::Detached;
+                if !watch.keep_running {
+                    let _ = channel.signal(Sig::TERM).await;
+                }
+                let _ = channel.close().await;
+                self.log(format!(
+                    "{} {}{}",
+                    "Detached:".bright_black(),
+                    reason,
+                    if watch.keep_running {
+                        ", command left running"
+                    } else {
+                        ""
+                    }
+                ))
+                .await;
+            }
+            Some(Trigger::Fail(reason)) => {
+                if !matches!(exit, RemoteExit::Status(_) | RemoteExit::Signal(_)) {
+                    let _ = channel.signal(Sig::TERM).await;
+                    let _ = channel.close().await;
+                }
+                exit = RemoteExit::Failed(reason);
+            }
+            None => {}
         }
+        if !expected_seen && matches!(exit, RemoteExit::Status(0) | RemoteExit::Detached) {
+            exit = RemoteExit::Failed(format!(
+                "no output matched expect_output '{}'",
+                watch.expect_output.as_ref().unwrap()
+            ));
+        }
+
+        // Ensure writing logs to file
+        self.flush_log_file().await;
 
         // Clear buffer
         self.remote_buffer = Arc::new(Mutex::new(VecDeque::new()));
+        exit
     }
 }
 
@@ -2,17 +2,32 @@ mod actions;
 mod config;
 mod finder;
 mod info;
+mod interpolate;
 mod logger;
+mod prompt;
+mod secrets;
+mod selection;
+mod ssh_config;
+mod validate;
 use crate::logger::Logger;
 use chrono::{DateTime, Local, Utc};
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use colored::*;
 use git2::Repository;
-use std::{fs, path::PathBuf, time::SystemTime};
+use std::{
+    collections::HashSet,
+    fs,
+    io::{stdout, IsTerminal},
+    path::PathBuf,
+    time::SystemTime,
+};
 
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, args_conflicts_with_subcommands = true)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     // to find config files in the folder
     #[arg(long)]
     find: Option<String>,
@@ -26,13 +41,258 @@ struct Cli {
     // actions from the config file to skip
     #[arg(long, use_value_delimiter = true)]
     skip: Option<Vec<String>>,
+
+    // only run these actions
+    #[arg(long, use_value_delimiter = true)]
+    only: Option<Vec<String>>,
+
+    // skip the actions before this one
+    #[arg(long)]
+    from: Option<String>,
+
+    // skip the actions after this one
+    #[arg(long)]
+    to: Option<String>,
+
+    // continue the last deployment of the config from the action that failed
+    // or was interrupted
+    #[arg(long, conflicts_with = "from")]
+    resume: bool,
+
+    // prefix remote output in the deployment log with the elapsed time
+    #[arg(long)]
+    timestamps: bool,
+
+    // number of servers deployed at the same time, overrides max_parallel
+    #[arg(long)]
+    parallel: Option<usize>,
+
+    // no cursor movements or prompts, automatic when stdout is not a terminal
+    #[arg(long, visible_alias = "plain")]
+    ci: bool,
+
+    // environment whose [env.<name>] section or sibling file is merged over
+    // the config file
+    #[arg(long)]
+    env: Option<String>,
+
+    // key=value, overrides the [vars] of the config file
+    #[arg(long = "var", value_parser = parse_var)]
+    vars: Vec<(String, String)>,
+
+    // print what would be done without running anything
+    #[arg(long)]
+    plan: bool,
+
+    // with --plan, connect to tell which uploaded files already exist
+    #[arg(long, requires = "plan")]
+    remote: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    // check a config without deploying it, as done before every deployment
+    Validate {
+        file: String,
+
+        #[arg(long)]
+        env: Option<String>,
+
+        #[arg(long = "var", value_parser = parse_var)]
+        vars: Vec<(String, String)>,
+
+        #[arg(long, use_value_delimiter = true)]
+        skip: Option<Vec<String>>,
+    },
+    // manage a passphrase-encrypted secrets file
+    Secrets {
+        #[command(subcommand)]
+        action: SecretsAction,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum SecretsAction {
+    // edit the decrypted file in $EDITOR, creating it if needed
+    Edit { file: String },
+    // print the decrypted file
+    View { file: String },
+}
+
+fn parse_var(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((name, value)) if !name.trim().is_empty() => {
+            Ok((name.trim().to_string(), value.to_string()))
+        }
+        _ => Err(format!("expected key=value, got '{}'", value)),
+    }
+}
+
+// HEAD of the repository in the current folder, if any
+fn git_info() -> interpolate::Git {
+    let mut git = interpolate::Git::default();
+    if let Ok(repo) = Repository::open(".") {
+        let head = repo.head().expect("Unable to access git HEAD");
+        if head.is_branch() {
+            git.branch = head.shorthand().map(String::from);
+        }
+        let head = head
+            .peel_to_commit()
+            .expect("Unable to pull reference to HEAD commit");
+        git.commit = Some(head.id().to_string());
+    }
+    git
+}
+
+// Loads the config and runs every check on it, returning all the errors found
+// along with the secrets to hide from them
+fn load_config(
+    config_path: &str,
+    environment: Option<&str>,
+    vars: &[(String, String)],
+    skip: &HashSet<String>,
+    git: interpolate::Git,
+) -> Result<config::Config, config::LoadError> {
+    let (config, mut errors, secret_values) =
+        match config::load(config_path, environment, vars, git) {
+            Ok(config) => {
+                let secret_values = config.secret_values.clone();
+                (Some(config), Vec::new(), secret_values)
+            }
+            Err(e) => (e.config.map(|config| *config), e.errors, e.secret_values),
+        };
+    // checked even when loading failed, to report everything at once
+    if let Some(config) = &config {
+        errors.extend(validate::check(config, skip));
+    }
+    match config {
+        Some(config) if errors.is_empty() => Ok(config),
+        _ => Err(config::LoadError {
+            config: None,
+            errors,
+            secret_values,
+        }),
+    }
+}
+
+async fn log_errors(logger: &mut Logger, errors: Vec<String>) {
+    for e in errors {
+        log!(logger, "{} {}", "Error:".bright_red(), e);
+    }
+}
+
+// Loads the config and the actions to skip from --skip, --only, --from,
+// --to and --resume. Logs every error and exits if any.
+async fn load_or_exit(
+    logger: &mut Logger,
+    config_path: &str,
+    args: &Cli,
+    previous_log: Option<PathBuf>,
+    git: interpolate::Git,
+) -> (config::Config, HashSet<String>) {
+    let skip: HashSet<String> = args.skip.clone().unwrap_or_default().into_iter().collect();
+    let config = match load_config(config_path, args.env.as_deref(), &args.vars, &skip, git) {
+        Ok(config) => config,
+        Err(e) => {
+            logger.set_secrets(&e.secret_values);
+            log_errors(logger, e.errors).await;
+            std::process::exit(1);
+        }
+    };
+    logger.set_secrets(&config.secret_values);
+
+    let mut from = args.from.clone();
+    if let Some(log) = previous_log {
+        match selection::resume_point(&config, &log) {
+            Ok(action) => {
+                log!(
+                    logger,
+                    "{} from '{}' ({})",
+                    "Resuming:".bright_black(),
+                    action,
+                    log.display()
+                );
+                from = Some(action);
+            }
+            Err(e) => {
+                log_errors(logger, vec![e]).await;
+                std::process::exit(1);
+            }
+        }
+    }
+    match selection::skipped_actions(
+        &config,
+        skip,
+        &args.only,
+        from.as_deref(),
+        args.to.as_deref(),
+    ) {
+        Ok(skip) => (config, skip),
+        Err(errors) => {
+            log_errors(logger, errors).await;
+            std::process::exit(1);
+        }
+    }
+}
+
+// Last deployment log of the config in the environment for --resume, exits
+// if there is none
+fn previous_log_or_exit(config_path: &str, args: &Cli) -> Option<PathBuf> {
+    if !args.resume {
+        return None;
+    }
+    match info::last_deployment(config_path, args.env.as_deref()) {
+        Some(log) => Some(log),
+        None => {
+            let environment = match &args.env {
+                Some(environment) => format!(" in environment '{}'", environment),
+                None => String::new(),
+            };
+            eprintln!(
+                "{} no previous deployment of '{}'{} to resume",
+                "Error:".bright_red(),
+                config_path,
+                environment
+            );
+            std::process::exit(1);
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() {
     let args = Cli::parse();
 
-    if args.info {
+    if let Some(Command::Validate {
+        file,
+        env,
+        vars,
+        skip,
+    }) = args.command
+    {
+        let skip: HashSet<String> = skip.unwrap_or_default().into_iter().collect();
+        match load_config(&file, env.as_deref(), &vars, &skip, git_info()) {
+            Ok(_) => println!("{} {}", "Valid:".bright_black(), file.bright_green()),
+            Err(e) => {
+                let mut logger = Logger::without_file();
+                logger.set_secrets(&e.secret_values);
+                for error in &e.errors {
+                    logger.print(&format!("{} {}", "Error:".bright_red(), error));
+                }
+                println!("{} error(s) found in {}", e.errors.len(), file);
+                std::process::exit(1);
+            }
+        }
+    } else if let Some(Command::Secrets { action }) = args.command {
+        let result = match action {
+            SecretsAction::Edit { file } => secrets::edit(&file),
+            SecretsAction::View { file } => secrets::view(&file),
+        };
+        if let Err(e) = result {
+            eprintln!("{} {}", "Error:".bright_red(), e);
+            std::process::exit(1);
+        }
+    } else if args.info {
         info::get_info();
     } else if let Some(start_folder) = args.find {
         let mut found: u32 = 0;
@@ -64,37 +324,40 @@ async fn main() {
         } else {
             println!("{} files found.", found.to_string().cyan());
         }
-    } else if let Some(config_path) = args.file {
+    } else if let (Some(config_path), true) = (&args.file, args.plan) {
+        let mut logger = Logger::without_file();
+        logger.set_plain(args.ci || !stdout().is_terminal());
+        log!(logger, "{} {}", "Plan:".bright_black(), config_path);
+        let previous_log = previous_log_or_exit(config_path, &args);
+        let (config, skip) =
+            load_or_exit(&mut logger, config_path, &args, previous_log, git_info()).await;
+        actions::plan::print_plan(&mut logger, &config, &skip, args.remote).await;
+    } else if let Some(config_path) = &args.file {
+        // read before the log of this deployment is created
+        let previous_log = previous_log_or_exit(config_path, &args);
         let mut logger = Logger::new();
-        log!(logger, "{} {}", "Loading:".bright_black(), &config_path);
+        logger.set_timestamps(args.timestamps);
+        logger.set_plain(args.ci || !stdout().is_terminal());
+        log!(logger, "{} {}", "Loading:".bright_black(), config_path);
         log!(
             logger,
             "{} {}",
             "Timestamp:".bright_black(),
             Utc::now().timestamp()
         );
-        let config = config::load(&config_path);
-        match Repository::open(".") {
-            Ok(repo) => {
-                let head = repo.head().expect("Unable to access git HEAD");
-                let head = head
-                    .peel_to_commit()
-                    .expect("Unable to pull reference to HEAD commit");
-                log!(
-                    logger,
-                    "{} {}",
-                    "Commit hash:".bright_black(),
-                    head.id().to_string()
-                );
-            }
-            Err(_) => {}
+        let git = git_info();
+        if let Some(commit) = &git.commit {
+            log!(logger, "{} {}", "Commit hash:".bright_black(), commit);
         }
+        if let Some(environment) = &args.env {
+            log!(logger, "{} {}", "Environment:".bright_black(), environment);
+        }
+        let (config, skip) = load_or_exit(&mut logger, config_path, &args, previous_log, git).await;
 
-        actions::runner::execute_actions(
-            &mut logger,
-            config,
-            args.skip.unwrap_or_else(Vec::new).into_iter().collect(),
-        )
-        .await;
+        let success =
+            actions::runner::execute_actions(&mut logger, config, skip, args.parallel).await;
+        if !success {
+            std::process::exit(1);
+        }
     }
 }
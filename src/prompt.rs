@@ -0,0 +1,64 @@
+use crossterm::{
+    event::{read, Event, KeyCode, KeyEvent},
+    terminal::{disable_raw_mode, enable_raw_mode},
+};
+use std::io::{stdout, Write};
+
+fn read_key() -> Option<KeyCode> {
+    loop {
+        match read() {
+            Ok(Event::Key(KeyEvent { code, .. })) => return Some(code),
+            Ok(_) => {}
+            Err(_) => return None,
+        }
+    }
+}
+
+// Asks a yes/no question, anything but 'y' is a no
+pub fn confirm(question: &str) -> bool {
+    print!("{} [y/N] ", question);
+    stdout().flush().unwrap();
+
+    enable_raw_mode().unwrap();
+    let answer = matches!(
+        read_key(),
+        Some(KeyCode::Char('y')) | Some(KeyCode::Char('Y'))
+    );
+    disable_raw_mode().unwrap();
+
+    println!("{}", if answer { "yes" } else { "no" });
+    answer
+}
+
+// Reads a line from the terminal, without echoing it back when hidden.
+// Returns None if the user pressed ESC.
+pub fn read_line(question: &str, hidden: bool) -> Option<String> {
+    print!("{} ", question);
+    stdout().flush().unwrap();
+
+    enable_raw_mode().unwrap();
+    let mut line = String::new();
+    let answer = loop {
+        match read_key() {
+            Some(KeyCode::Enter) => break Some(line),
+            Some(KeyCode::Esc) | None => break None,
+            Some(KeyCode::Backspace) => {
+                if line.pop().is_some() && !hidden {
+                    print!("\u{8} \u{8}");
+                }
+            }
+            Some(KeyCode::Char(c)) => {
+                line.push(c);
+                if !hidden {
+                    print!("{}", c);
+                }
+            }
+            _ => {}
+        }
+        stdout().flush().unwrap();
+    };
+    disable_raw_mode().unwrap();
+
+    println!();
+    answer
+}
@@ -0,0 +1,290 @@
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{stdin, IsTerminal, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::process::{self, Command};
+
+use argon2::Argon2;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use colored::Colorize;
+
+use crate::config::Secrets;
+use crate::prompt;
+
+// First line of encrypted files, followed by the salt, the nonce and the
+// ciphertext in base64
+const HEADER: &str = "deploy-secrets v1";
+const PASSPHRASE_ENV: &str = "DEPLOY_SECRETS_PASSPHRASE";
+const SALT_SIZE: usize = 16;
+
+// KEY=VALUE lines, with optional "export", quotes and # comments
+fn parse_dotenv(contents: &str, path: &Path) -> Result<Vec<(String, String)>, String> {
+    let mut values = Vec::new();
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let (name, value) = line
+            .split_once('=')
+            .ok_or_else(|| format!("{} line {}: expected KEY=VALUE", path.display(), index + 1))?;
+        let value = value.trim();
+        let value = match value.chars().next() {
+            Some(quote @ ('"' | '\'')) if value.len() > 1 && value.ends_with(quote) => {
+                &value[1..value.len() - 1]
+            }
+            _ => value,
+        };
+        values.push((name.trim().to_string(), value.to_string()));
+    }
+    Ok(values)
+}
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> Result<Key, String> {
+    let mut key = Key::default();
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| format!("unable to derive the key: {}", e))?;
+    Ok(key)
+}
+
+fn encrypt(plaintext: &str, passphrase: &str) -> Result<String, String> {
+    let mut salt = [0u8; SALT_SIZE];
+    OsRng.fill_bytes(&mut salt);
+    let cipher = ChaCha20Poly1305::new(&derive_key(passphrase, &salt)?);
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, plaintext.as_bytes())
+        .map_err(|_| "unable to encrypt the secrets".to_string())?;
+    Ok(format!(
+        "{}\n{}\n{}\n{}\n",
+        HEADER,
+        STANDARD.encode(salt),
+        STANDARD.encode(nonce),
+        STANDARD.encode(ciphertext)
+    ))
+}
+
+fn decrypt(contents: &str, passphrase: &str, path: &Path) -> Result<String, String> {
+    let invalid = || format!("'{}' is not an encrypted secrets file", path.display());
+    let mut lines = contents.lines();
+    if lines.next() != Some(HEADER) {
+        return Err(invalid());
+    }
+    let mut decode = || {
+        lines
+            .next()
+            .and_then(|line| STANDARD.decode(line.trim()).ok())
+            .ok_or_else(invalid)
+    };
+    let (salt, nonce, ciphertext) = (decode()?, decode()?, decode()?);
+    if nonce.len() != 12 {
+        return Err(invalid());
+    }
+    let cipher = ChaCha20Poly1305::new(&derive_key(passphrase, &salt)?);
+    let plaintext = cipher
+        .decrypt(Nonce::from_slice(&nonce), ciphertext.as_ref())
+        .map_err(|_| {
+            format!(
+                "unable to decrypt '{}', wrong passphrase or modified file",
+                path.display()
+            )
+        })?;
+    String::from_utf8(plaintext).map_err(|_| invalid())
+}
+
+// From $DEPLOY_SECRETS_PASSPHRASE or asked, twice for a new file
+fn passphrase(path: &Path, new: bool) -> Result<String, String> {
+    if let Ok(passphrase) = env::var(PASSPHRASE_ENV) {
+        return Ok(passphrase);
+    }
+    if !stdin().is_terminal() {
+        return Err(format!(
+            "no terminal to ask the passphrase of '{}', set ${}",
+            path.display(),
+            PASSPHRASE_ENV
+        ));
+    }
+    let cancelled = || "cancelled".to_string();
+    let passphrase = prompt::read_line(&format!("Passphrase for '{}':", path.display()), true)
+        .ok_or_else(cancelled)?;
+    if new {
+        let repeated = prompt::read_line("Repeat the passphrase:", true).ok_or_else(cancelled)?;
+        if repeated != passphrase {
+            return Err("the passphrases do not match".to_string());
+        }
+        if passphrase.is_empty() {
+            return Err("the passphrase cannot be empty".to_string());
+        }
+    }
+    Ok(passphrase)
+}
+
+fn read_encrypted(path: &Path) -> Result<String, String> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| format!("unable to read '{}': {}", path.display(), e))?;
+    decrypt(&contents, &passphrase(path, false)?, path)
+}
+
+// Decrypts the file into a private temporary file opened in $VISUAL or
+// $EDITOR, then encrypts it back. Creates the file if it does not exist.
+pub fn edit(file: &str) -> Result<(), String> {
+    let path = Path::new(file);
+    let new = !path.exists();
+    let passphrase = passphrase(path, new)?;
+    let original = if new {
+        String::new()
+    } else {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("unable to read '{}': {}", path.display(), e))?;
+        decrypt(&contents, &passphrase, path)?
+    };
+
+    let temp_path = env::temp_dir().join(format!("deploy-secrets-{}.env", process::id()));
+    let mut temp_file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&temp_path)
+        .map_err(|e| format!("unable to create '{}': {}", temp_path.display(), e))?;
+    temp_file
+        .write_all(original.as_bytes())
+        .map_err(|e| format!("unable to write '{}': {}", temp_path.display(), e))?;
+    drop(temp_file);
+
+    let editor = env::var("VISUAL")
+        .or_else(|_| env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_string());
+    let edited = loop {
+        // through sh so that editors given with arguments work
+        let status = Command::new("sh")
+            .arg("-c")
+            .arg(format!("{} \"$1\"", editor))
+            .arg("sh")
+            .arg(&temp_path)
+            .status();
+        let contents = match status {
+            Ok(status) if status.success() => fs::read_to_string(&temp_path)
+                .map_err(|e| format!("unable to read '{}': {}", temp_path.display(), e)),
+            Ok(status) => Err(format!("'{}' exited with {}", editor, status)),
+            Err(e) => Err(format!("unable to start '{}': {}", editor, e)),
+        };
+        match contents.and_then(|contents| parse_dotenv(&contents, path).map(|_| contents)) {
+            Ok(contents) => break Ok(contents),
+            Err(e) => {
+                println!("{} {}", "Error:".bright_red(), e);
+                if !prompt::confirm("Edit again?") {
+                    break Err("secrets left unchanged".to_string());
+                }
+            }
+        }
+    };
+    let _ = fs::remove_file(&temp_path);
+
+    let edited = edited?;
+    if edited == original && !new {
+        println!("{}", "No changes".bright_black());
+        return Ok(());
+    }
+    fs::write(path, encrypt(&edited, &passphrase)?)
+        .map_err(|e| format!("unable to write '{}': {}", path.display(), e))?;
+    println!("{} {}", "Encrypted:".bright_black(), path.display());
+    Ok(())
+}
+
+pub fn view(file: &str) -> Result<(), String> {
+    print!("{}", read_encrypted(Path::new(file))?);
+    Ok(())
+}
+
+// Reads every secret of the [secrets] table, its paths were made relative to
+// the config file declaring them
+pub fn load(secrets: &Secrets) -> Result<Vec<(String, String)>, String> {
+    let mut values = Vec::new();
+    for name in &secrets.env {
+        let value = env::var(name)
+            .map_err(|_| format!("secret environment variable '{}' is not set", name))?;
+        values.push((name.clone(), value));
+    }
+    if let Some(dotenv) = &secrets.dotenv {
+        let path = Path::new(dotenv);
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("unable to read '{}': {}", path.display(), e))?;
+        values.extend(parse_dotenv(&contents, path)?);
+    }
+    if let Some(file) = &secrets.file {
+        let path = Path::new(file);
+        values.extend(parse_dotenv(&read_encrypted(path)?, path)?);
+    }
+    Ok(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_dotenv_lines() {
+        let contents = "# database\n\
+                        DATABASE_PASSWORD=p@ss=word\n\
+                        export API_KEY = \"quoted value\"\n\
+                        \n\
+                        TOKEN='single'\n\
+                        EMPTY=\n\
+                        LONE_QUOTE=\"\n";
+        let values = parse_dotenv(contents, Path::new(".env")).unwrap();
+        let expected = [
+            ("DATABASE_PASSWORD", "p@ss=word"),
+            ("API_KEY", "quoted value"),
+            ("TOKEN", "single"),
+            ("EMPTY", ""),
+            ("LONE_QUOTE", "\""),
+        ];
+        assert_eq!(values.len(), expected.len());
+        for ((name, value), (expected_name, expected_value)) in values.iter().zip(expected) {
+            assert_eq!(
+                (name.as_str(), value.as_str()),
+                (expected_name, expected_value)
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_lines_without_values() {
+        let error = parse_dotenv("A=1\nNOT A VALUE\n", Path::new(".env")).unwrap_err();
+        assert_eq!(error, ".env line 2: expected KEY=VALUE");
+    }
+
+    #[test]
+    fn decrypts_what_it_encrypts() {
+        let path = Path::new("prod.secrets");
+        let plaintext = "DATABASE_PASSWORD=it's a secret\n";
+        let encrypted = encrypt(plaintext, "passphrase").unwrap();
+        assert!(encrypted.starts_with(HEADER));
+        assert!(!encrypted.contains("secret\n"));
+        assert_eq!(decrypt(&encrypted, "passphrase", path).unwrap(), plaintext);
+
+        // a new salt and nonce every time
+        assert_ne!(encrypt(plaintext, "passphrase").unwrap(), encrypted);
+    }
+
+    #[test]
+    fn refuses_wrong_passphrases_and_modified_files() {
+        let path = Path::new("prod.secrets");
+        let encrypted = encrypt("TOKEN=abc\n", "passphrase").unwrap();
+        assert!(decrypt(&encrypted, "wrong", path).is_err());
+
+        let mut lines: Vec<String> = encrypted.lines().map(String::from).collect();
+        let mut ciphertext = STANDARD.decode(&lines[3]).unwrap();
+        ciphertext[0] ^= 1;
+        lines[3] = STANDARD.encode(ciphertext);
+        assert!(decrypt(&lines.join("\n"), "passphrase", path).is_err());
+
+        assert!(decrypt("TOKEN=abc\n", "passphrase", path).is_err());
+    }
+}
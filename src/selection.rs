@@ -0,0 +1,257 @@
+use std::collections::HashSet;
+use std::path::Path;
+
+use crate::config::Config;
+use crate::info::extract_actions;
+
+fn action_index(config: &Config, name: &str, option: &str) -> Result<usize, String> {
+    config
+        .actions
+        .iter()
+        .position(|action| action.name() == name)
+        .ok_or_else(|| format!("{} '{}' matches no action", option, name))
+}
+
+// Adds to `skip` every action left out by --only and the --from/--to range,
+// so the deployment only needs to know what to skip
+pub fn skipped_actions(
+    config: &Config,
+    mut skip: HashSet<String>,
+    only: &Option<Vec<String>>,
+    from: Option<&str>,
+    to: Option<&str>,
+) -> Result<HashSet<String>, Vec<String>> {
+    let mut errors = Vec::new();
+    let mut index = |name: Option<&str>, option: &str, default: usize| match name {
+        Some(name) => action_index(config, name, option).unwrap_or_else(|e| {
+            errors.push(e);
+            default
+        }),
+        None => default,
+    };
+    let first = index(from, "--from", 0);
+    let last = index(to, "--to", config.actions.len().saturating_sub(1));
+    if first > last {
+        errors.push(format!(
+            "--from '{}' comes after --to '{}'",
+            from.unwrap_or_default(),
+            to.unwrap_or_default()
+        ));
+    }
+    for name in only.iter().flatten() {
+        if let Err(e) = action_index(config, name, "--only") {
+            errors.push(e);
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    for (i, action) in config.actions.iter().enumerate() {
+        let selected = match only {
+            Some(only) => only.iter().any(|name| name == action.name()),
+            None => true,
+        };
+        if i < first || i > last || !selected {
+            skip.insert(action.name().to_string());
+        }
+    }
+    Ok(skip)
+}
+
+// Index of the action a host resumes from: the first one that failed or was
+// interrupted, or the one after the last that finished. None if the host ran
+// no action.
+fn host_resume_index(
+    names: &[&str],
+    host: &str,
+    started: &[(String, String)],
+    finished: &HashSet<(String, String)>,
+) -> Option<usize> {
+    let position =
+        |(_, name): &(String, String)| names.iter().position(|known| *known == name.as_str());
+    let host_started: Vec<&(String, String)> = started
+        .iter()
+        .filter(|(started_host, _)| started_host == host)
+        .collect();
+    if host_started.is_empty() {
+        return None;
+    }
+    let incomplete = host_started
+        .iter()
+        .filter(|started| !finished.contains(**started))
+        .filter_map(|started| position(started))
+        .min();
+    if incomplete.is_some() {
+        return incomplete;
+    }
+    let last_finished = finished
+        .iter()
+        .filter(|(finished_host, _)| finished_host == host)
+        .filter_map(position)
+        .max();
+    Some(last_finished.map_or(0, |index| index + 1))
+}
+
+// Action to resume from, the earliest among the hosts so that none of them
+// skips an action it did not finish. Hosts that ran no action in the previous
+// deployment cannot be resumed.
+pub fn resume_point(config: &Config, log: &Path) -> Result<String, String> {
+    let (started, finished) =
+        extract_actions(log).map_err(|e| format!("unable to read '{}': {}", log.display(), e))?;
+
+    let names: Vec<&str> = config.actions.iter().map(|action| action.name()).collect();
+    let mut point = None;
+    let mut not_started = Vec::new();
+    for host in config.servers.keys() {
+        match host_resume_index(&names, host, &started, &finished) {
+            Some(index) => point = Some(point.map_or(index, |point: usize| point.min(index))),
+            None => {
+                let targeted = config.actions.iter().any(|action| {
+                    config
+                        .action_hosts(action)
+                        .unwrap_or_default()
+                        .contains(host)
+                });
+                if targeted {
+                    not_started.push(host.as_str());
+                }
+            }
+        }
+    }
+    if !not_started.is_empty() {
+        return Err(format!(
+            "cannot resume, no action ran on {} in '{}', deploy without --resume",
+            not_started
+                .iter()
+                .map(|host| format!("'{}'", host))
+                .collect::<Vec<String>>()
+                .join(", "),
+            log.display()
+        ));
+    }
+
+    point
+        .and_then(|index| names.get(index))
+        .map(|name| name.to_string())
+        .ok_or_else(|| {
+            format!(
+                "every action finished in '{}', there is nothing to resume",
+                log.display()
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn config() -> Config {
+        toml::from_str(
+            r#"
+            [servers.api-1]
+            host = "10.0.0.1"
+
+            [servers.api-2]
+            host = "10.0.0.2"
+
+            [[actions]]
+            type = "commands"
+            name = "stop"
+            commands = ["docker-compose down"]
+
+            [[actions]]
+            type = "upload"
+            name = "upload"
+            source_folder = "."
+            target_folder = "~/app"
+
+            [[actions]]
+            type = "commands"
+            name = "start"
+            commands = ["docker-compose up -d"]
+            "#,
+        )
+        .unwrap()
+    }
+
+    fn names(skip: &HashSet<String>) -> Vec<&str> {
+        let mut names: Vec<&str> = skip.iter().map(String::as_str).collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn skips_actions_outside_the_selection() {
+        let config = config();
+        let only = Some(vec!["upload".to_string()]);
+        let skip = skipped_actions(&config, HashSet::new(), &only, None, None).unwrap();
+        assert_eq!(names(&skip), ["start", "stop"]);
+
+        let skip = skipped_actions(&config, HashSet::new(), &None, Some("upload"), None).unwrap();
+        assert_eq!(names(&skip), ["stop"]);
+
+        let skip = skipped_actions(&config, HashSet::new(), &None, None, Some("upload")).unwrap();
+        assert_eq!(names(&skip), ["start"]);
+
+        let skip = HashSet::from(["start".to_string()]);
+        let skip = skipped_actions(&config, skip, &None, Some("upload"), None).unwrap();
+        assert_eq!(names(&skip), ["start", "stop"]);
+    }
+
+    #[test]
+    fn reports_every_invalid_selection() {
+        let config = config();
+        let only = Some(vec!["restart".to_string()]);
+        let errors =
+            skipped_actions(&config, HashSet::new(), &only, Some("build"), None).unwrap_err();
+        assert_eq!(errors.len(), 2);
+
+        let errors = skipped_actions(&config, HashSet::new(), &None, Some("start"), Some("stop"))
+            .unwrap_err();
+        assert_eq!(errors, ["--from 'start' comes after --to 'stop'"]);
+    }
+
+    fn resume_from(test: &str, log: &str) -> Result<String, String> {
+        let path =
+            std::env::temp_dir().join(format!("deploy-resume-{}-{}.txt", test, std::process::id()));
+        fs::write(&path, log).unwrap();
+        let resumed = resume_point(&config(), &path);
+        fs::remove_file(&path).unwrap();
+        resumed
+    }
+
+    #[test]
+    fn resumes_from_the_earliest_unfinished_action() {
+        let log = "Host: api-1\nAction: stop\nFinished: stop\nAction: upload\n\
+                   Host: api-2\nAction: stop\nFinished: stop\nAction: upload\nFinished: upload\n";
+        assert_eq!(resume_from("unfinished", log).unwrap(), "upload");
+
+        // hosts deployed in parallel have their lines prefixed
+        let log = "[api-1] Host: api-1\n[api-2] Host: api-2\n[api-1] Action: stop\n\
+                   [api-2] Action: stop\n[api-2] Finished: stop\n[api-1] Finished: stop\n\
+                   [api-1] Action: upload\n[api-1] Finished: upload\n";
+        assert_eq!(resume_from("parallel", log).unwrap(), "upload");
+    }
+
+    #[test]
+    fn refuses_to_resume_hosts_that_did_not_run() {
+        let log = "Host: api-1\nAction: stop\nFinished: stop\nAction: upload\nFinished: upload\n\
+                   Action: start\nError: action 'start' failed\nSummary: api-1 failed, api-2 not deployed\n";
+        let error = resume_from("not-started", log).unwrap_err();
+        assert!(error.contains("'api-2'"), "{}", error);
+    }
+
+    #[test]
+    fn has_nothing_to_resume_once_every_action_finished() {
+        let mut log = String::new();
+        for host in ["api-1", "api-2"] {
+            log.push_str(&format!("Host: {}\n", host));
+            for action in ["stop", "upload", "start"] {
+                log.push_str(&format!("Action: {}\nFinished: {}\n", action, action));
+            }
+        }
+        assert!(resume_from("finished", &log).is_err());
+    }
+}
@@ -0,0 +1,194 @@
+use std::fs;
+
+use crate::actions::expand_user_path;
+
+const SSH_CONFIG: &str = "~/.ssh/config";
+
+// Settings of ~/.ssh/config applying to a host alias
+#[derive(Default)]
+pub struct HostConfig {
+    pub hostname: Option<String>,
+    pub port: Option<u16>,
+    pub user: Option<String>,
+    pub identity_files: Vec<String>,
+    pub proxy_jump: Option<String>,
+}
+
+// Glob matching with '*' and '?', as used by Host patterns
+fn matches_pattern(pattern: &str, value: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let value: Vec<char> = value.chars().collect();
+    let (mut p, mut v) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+    while v < value.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == value[v]) {
+            p += 1;
+            v += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, v));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            p = star + 1;
+            v = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+// A Host line matches if any pattern matches and no negated one does
+fn matches_host(patterns: &[String], alias: &str) -> bool {
+    let mut matched = false;
+    for pattern in patterns {
+        match pattern.strip_prefix('!') {
+            Some(negated) if matches_pattern(negated, alias) => return false,
+            Some(_) => {}
+            None => matched |= matches_pattern(pattern, alias),
+        }
+    }
+    matched
+}
+
+fn split_line(line: &str) -> Option<(String, String)> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+    let split = line.find(|c: char| c.is_whitespace() || c == '=')?;
+    let keyword = line[..split].to_lowercase();
+    let value = line[split..]
+        .trim_start_matches(|c: char| c.is_whitespace() || c == '=')
+        .trim()
+        .trim_matches('"')
+        .to_string();
+    Some((keyword, value))
+}
+
+pub fn parse(contents: &str, alias: &str) -> Result<HostConfig, String> {
+    let mut config = HostConfig::default();
+    // settings before the first Host line apply to every host
+    let mut active = true;
+    for (index, line) in contents.lines().enumerate() {
+        let Some((keyword, value)) = split_line(line) else {
+            continue;
+        };
+        match keyword.as_str() {
+            "host" => {
+                let patterns: Vec<String> = value.split_whitespace().map(String::from).collect();
+                active = matches_host(&patterns, alias);
+            }
+            // Match blocks are not supported, their settings are ignored
+            "match" => active = false,
+            _ if !active => {}
+            // the first value obtained for a setting is the one used
+            "hostname" if config.hostname.is_none() => {
+                config.hostname = Some(value.replace("%h", alias));
+            }
+            "port" if config.port.is_none() => {
+                config.port = Some(value.parse().map_err(|_| {
+                    format!(
+                        "{} line {}: invalid port '{}'",
+                        SSH_CONFIG,
+                        index + 1,
+                        value
+                    )
+                })?);
+            }
+            "user" if config.user.is_none() => config.user = Some(value),
+            "identityfile" => config.identity_files.push(value),
+            "proxyjump" if config.proxy_jump.is_none() && value != "none" => {
+                config.proxy_jump = Some(value);
+            }
+            _ => {}
+        }
+    }
+    Ok(config)
+}
+
+// Looks the alias up in ~/.ssh/config, missing files give empty settings
+pub fn lookup(alias: &str) -> Result<HostConfig, String> {
+    match fs::read_to_string(expand_user_path(SSH_CONFIG)) {
+        Ok(contents) => parse(&contents, alias),
+        Err(_) => Ok(HostConfig::default()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+# applies to every host
+IdentityFile ~/.ssh/global
+
+Host web
+    HostName 10.0.0.5
+    Port 2222
+    User deploy
+    IdentityFile ~/.ssh/web
+    ProxyJump bastion
+
+Host *.internal !db.internal
+    HostName %h.example.com
+    User=internal
+
+Host db.internal
+    proxyjump none
+
+Match host web
+    User ignored
+
+Host *
+    User default
+    Port 22
+"#;
+
+    #[test]
+    fn reads_the_settings_of_an_alias() {
+        let config = parse(CONFIG, "web").unwrap();
+        assert_eq!(config.hostname.as_deref(), Some("10.0.0.5"));
+        assert_eq!(config.port, Some(2222));
+        assert_eq!(config.user.as_deref(), Some("deploy"));
+        assert_eq!(config.identity_files, ["~/.ssh/global", "~/.ssh/web"]);
+        assert_eq!(config.proxy_jump.as_deref(), Some("bastion"));
+    }
+
+    #[test]
+    fn matches_wildcard_and_negated_patterns() {
+        let config = parse(CONFIG, "api.internal").unwrap();
+        assert_eq!(config.hostname.as_deref(), Some("api.internal.example.com"));
+        assert_eq!(config.user.as_deref(), Some("internal"));
+        assert_eq!(config.port, Some(22));
+
+        let config = parse(CONFIG, "db.internal").unwrap();
+        assert_eq!(config.hostname, None);
+        assert_eq!(config.user.as_deref(), Some("default"));
+        assert_eq!(config.proxy_jump, None);
+    }
+
+    #[test]
+    fn falls_back_to_global_settings() {
+        let config = parse(CONFIG, "unknown").unwrap();
+        assert_eq!(config.hostname, None);
+        assert_eq!(config.user.as_deref(), Some("default"));
+        assert_eq!(config.identity_files, ["~/.ssh/global"]);
+    }
+
+    #[test]
+    fn matches_glob_patterns() {
+        assert!(matches_pattern("*", ""));
+        assert!(matches_pattern("web-?", "web-1"));
+        assert!(!matches_pattern("web-?", "web-10"));
+        assert!(matches_pattern("*.example.*", "api.example.com"));
+        assert!(!matches_pattern("*.example.com", "example.com"));
+    }
+
+    #[test]
+    fn rejects_invalid_ports() {
+        assert!(parse("Host web\n  Port ssh\n", "web").is_err());
+        // settings of other hosts are not read
+        assert!(parse("Host web\n  Port ssh\n", "api").is_ok());
+    }
+}
@@ -0,0 +1,427 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use serde::de::DeserializeOwned;
+use toml_edit::{ImDocument, Item};
+
+use crate::actions::expand_user_path;
+use crate::actions::runner::remote_watch;
+use crate::config::{Action, AuthMethod, Config, Server};
+
+const CONFIG_FIELDS: &[&str] = &[
+    "server",
+    "servers",
+    "groups",
+    "max_parallel",
+    "serial",
+    "max_fail_percentage",
+    "canary",
+    "actions",
+];
+const SERVER_FIELDS: &[&str] = &[
+    "host",
+    "port",
+    "user",
+    "ssh_config_host",
+    "ssh_key",
+    "auth",
+    "host_key_fingerprint",
+    "jump",
+];
+const COMMANDS_FIELDS: &[&str] = &[
+    "type",
+    "name",
+    "commands",
+    "continue_on_error",
+    "merge_stderr",
+    "detach_on",
+    "detach_after",
+    "keep_running",
+    "fail_on",
+    "expect_output",
+    "hosts",
+];
+const UPLOAD_FIELDS: &[&str] = &[
+    "type",
+    "name",
+    "source_folder",
+    "target_folder",
+    "templates",
+    "hosts",
+];
+const CANARY_FIELDS: &[&str] = &["host", "verify", "confirm"];
+
+// Step of a path in the config document
+#[derive(Clone)]
+pub enum Segment {
+    Key(String),
+    Index(usize),
+}
+
+// Such as "servers.web.auth[0].path"
+pub fn format_path(path: &[Segment]) -> String {
+    let mut formatted = String::new();
+    for segment in path {
+        match segment {
+            Segment::Key(key) if formatted.is_empty() => formatted.push_str(key),
+            Segment::Key(key) => formatted.push_str(&format!(".{}", key)),
+            Segment::Index(index) => formatted.push_str(&format!("[{}]", index)),
+        }
+    }
+    formatted
+}
+
+fn child(path: &[Segment], segment: Segment) -> Vec<Segment> {
+    let mut child = path.to_vec();
+    child.push(segment);
+    child
+}
+
+// Keys of the table missing from `known`
+fn check_keys(
+    table: &toml::Table,
+    known: &[&str],
+    path: &[Segment],
+    unknown: &mut Vec<Vec<Segment>>,
+) {
+    for key in table.keys() {
+        if !known.contains(&key.as_str()) {
+            unknown.push(child(path, Segment::Key(key.clone())));
+        }
+    }
+}
+
+fn check_server(value: &toml::Value, path: &[Segment], unknown: &mut Vec<Vec<Segment>>) {
+    let Some(server) = value.as_table() else {
+        return;
+    };
+    check_keys(server, SERVER_FIELDS, path, unknown);
+    let auth_path = child(path, Segment::Key("auth".to_string()));
+    for (index, method) in tables(server.get("auth")) {
+        let known: &[&str] = match method.get("type").and_then(|t| t.as_str()) {
+            Some("agent") | Some("keyboard-interactive") => &["type"],
+            Some("key") => &["type", "path", "passphrase_env"],
+            Some("password") => &["type", "password_env"],
+            // unknown types are reported when deserializing
+            _ => continue,
+        };
+        check_keys(
+            method,
+            known,
+            &child(&auth_path, Segment::Index(index)),
+            unknown,
+        );
+    }
+    let jump_path = child(path, Segment::Key("jump".to_string()));
+    if let Some(toml::Value::Array(hops)) = server.get("jump") {
+        for (index, hop) in hops.iter().enumerate() {
+            check_server(hop, &child(&jump_path, Segment::Index(index)), unknown);
+        }
+    }
+}
+
+fn check_actions(value: Option<&toml::Value>, path: &[Segment], unknown: &mut Vec<Vec<Segment>>) {
+    for (index, action) in tables(value) {
+        let known = match action.get("type").and_then(|t| t.as_str()) {
+            Some("commands") => COMMANDS_FIELDS,
+            Some("upload") => UPLOAD_FIELDS,
+            // unknown types are reported when deserializing
+            _ => continue,
+        };
+        check_keys(action, known, &child(path, Segment::Index(index)), unknown);
+    }
+}
+
+// The tables of an array of tables, with their index
+fn tables(value: Option<&toml::Value>) -> impl Iterator<Item = (usize, &toml::Table)> {
+    value
+        .and_then(|value| value.as_array())
+        .into_iter()
+        .flatten()
+        .enumerate()
+        .filter_map(|(index, value)| value.as_table().map(|table| (index, table)))
+}
+
+fn key(key: &str) -> Vec<Segment> {
+    vec![Segment::Key(key.to_string())]
+}
+
+// Fields of the config that would be silently ignored, most likely typos
+pub fn unknown_fields(document: &toml::Table) -> Vec<Vec<Segment>> {
+    let mut unknown = Vec::new();
+    check_keys(document, CONFIG_FIELDS, &[], &mut unknown);
+    if let Some(server) = document.get("server") {
+        check_server(server, &key("server"), &mut unknown);
+    }
+    if let Some(toml::Value::Table(servers)) = document.get("servers") {
+        for (name, server) in servers {
+            let path = child(&key("servers"), Segment::Key(name.clone()));
+            check_server(server, &path, &mut unknown);
+        }
+    }
+    if let Some(toml::Value::Table(canary)) = document.get("canary") {
+        check_keys(canary, CANARY_FIELDS, &key("canary"), &mut unknown);
+        let verify_path = child(&key("canary"), Segment::Key("verify".to_string()));
+        check_actions(canary.get("verify"), &verify_path, &mut unknown);
+    }
+    check_actions(document.get("actions"), &key("actions"), &mut unknown);
+    unknown
+}
+
+// Deserializes the value found at `path`, errors come with the path of the
+// field at fault
+pub fn deserialize<T: DeserializeOwned>(
+    value: toml::Value,
+    path: &[Segment],
+) -> Result<T, (Vec<Segment>, String)> {
+    serde_path_to_error::deserialize(value).map_err(|e| {
+        let mut full_path = path.to_vec();
+        for segment in e.path().iter() {
+            match segment {
+                serde_path_to_error::Segment::Seq { index } => {
+                    full_path.push(Segment::Index(*index))
+                }
+                serde_path_to_error::Segment::Map { key } => {
+                    full_path.push(Segment::Key(key.clone()))
+                }
+                _ => {}
+            }
+        }
+        (full_path, e.into_inner().message().to_string())
+    })
+}
+
+// Type errors and missing fields of the config. Servers and actions are
+// deserialized one by one to report all of theirs, the rest of the config
+// is checked once they are valid since deserializing stops at the first error.
+pub fn type_errors(document: &toml::Table) -> Vec<(Vec<Segment>, String)> {
+    let mut errors = Vec::new();
+    let mut servers = Vec::new();
+    if let Some(server) = document.get("server") {
+        servers.push((key("server"), server));
+    }
+    if let Some(toml::Value::Table(named)) = document.get("servers") {
+        for (name, server) in named {
+            servers.push((child(&key("servers"), Segment::Key(name.clone())), server));
+        }
+    }
+    for (path, server) in servers {
+        if let Err(error) = deserialize::<Server>(server.clone(), &path) {
+            errors.push(error);
+        }
+    }
+    if let Some(toml::Value::Array(actions)) = document.get("actions") {
+        for (index, action) in actions.iter().enumerate() {
+            let path = child(&key("actions"), Segment::Index(index));
+            if let Err(error) = deserialize::<Action>(action.clone(), &path) {
+                errors.push(error);
+            }
+        }
+    }
+    if errors.is_empty() {
+        if let Err(error) = deserialize::<Config>(toml::Value::Table(document.clone()), &[]) {
+            errors.push(error);
+        }
+    }
+    errors
+}
+
+// Step of a path in a file, actions are matched by name since their index
+// changes once includes and extends are merged
+#[derive(Clone, Copy)]
+enum Step<'a> {
+    Key(&'a str),
+    Index(usize),
+    Named(&'a str),
+}
+
+impl Step<'_> {
+    fn get<'i>(&self, item: &'i Item) -> Option<&'i Item> {
+        match self {
+            Step::Key(key) => item.get(*key),
+            Step::Index(index) => item.get(*index),
+            Step::Named(name) => (0usize..)
+                .map_while(|index| item.get(index))
+                .find(|action| action.get("name").and_then(|n| n.as_str()) == Some(*name)),
+        }
+    }
+}
+
+// Steps of the path, with the names of the actions it goes through taken
+// from the merged document
+fn steps<'a>(document: &'a toml::Table, path: &'a [Segment]) -> Vec<Step<'a>> {
+    let mut steps = Vec::new();
+    let mut value: Option<&toml::Value> = None;
+    let mut previous_key = "";
+    for segment in path {
+        match segment {
+            Segment::Key(key) => {
+                value = match value {
+                    Some(value) => value.get(key),
+                    None if steps.is_empty() => document.get(key),
+                    None => None,
+                };
+                previous_key = key.as_str();
+                steps.push(Step::Key(key));
+            }
+            Segment::Index(index) => {
+                value = value.and_then(|value| value.get(*index));
+                let name = value
+                    .and_then(|action| action.get("name"))
+                    .and_then(|name| name.as_str());
+                match name {
+                    Some(name) if previous_key == "actions" || previous_key == "verify" => {
+                        steps.push(Step::Named(name))
+                    }
+                    _ => steps.push(Step::Index(*index)),
+                }
+            }
+        }
+    }
+    steps
+}
+
+// Span of the key, or of the item when it has none such as for an action
+fn find_span(root: &Item, steps: &[Step]) -> Option<Range<usize>> {
+    let (last, parents) = steps.split_last()?;
+    let mut parent = root;
+    for step in parents {
+        parent = step.get(parent)?;
+    }
+    let item = last.get(parent)?;
+    let key_span = match last {
+        Step::Key(key) => parent
+            .as_table_like()
+            .and_then(|table| table.key(key))
+            .and_then(|key| key.span()),
+        _ => None,
+    };
+    key_span.or_else(|| item.span())
+}
+
+// Line and column of the byte offset, from 1
+fn line_column(contents: &str, offset: usize) -> (usize, usize) {
+    let before = &contents[..offset.min(contents.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before
+        .rsplit('\n')
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .count()
+        + 1;
+    (line, column)
+}
+
+// "file:line:column" of the path of the merged document in the files read,
+// ordered by precedence. The closest enclosing table is given when the path
+// itself is missing, such as for a missing field.
+pub fn locate(
+    files: &[(PathBuf, String)],
+    document: &toml::Table,
+    environment: Option<&str>,
+    path: &[Segment],
+) -> Option<String> {
+    let steps = steps(document, path);
+    let parsed: Vec<(&PathBuf, &String, ImDocument<&str>)> = files
+        .iter()
+        .filter_map(|(file, contents)| {
+            ImDocument::parse(contents.as_str())
+                .ok()
+                .map(|parsed| (file, contents, parsed))
+        })
+        .collect();
+    for length in (1..=steps.len()).rev() {
+        let steps = &steps[..length];
+        // an environment can also be a section of the file
+        let in_environment: Option<Vec<Step>> = environment.map(|environment| {
+            [Step::Key("env"), Step::Key(environment)]
+                .into_iter()
+                .chain(steps.iter().copied())
+                .collect()
+        });
+        for (file, contents, parsed) in &parsed {
+            let span = in_environment
+                .as_deref()
+                .and_then(|steps| find_span(parsed.as_item(), steps))
+                .or_else(|| find_span(parsed.as_item(), steps));
+            if let Some(span) = span {
+                let (line, column) = line_column(contents, span.start);
+                return Some(format!("{}:{}:{}", file.display(), line, column));
+            }
+        }
+    }
+    None
+}
+
+fn check_key_file(path: &str, owner: &str, errors: &mut Vec<String>) {
+    let expanded = expand_user_path(path);
+    if let Err(e) = File::open(&expanded) {
+        errors.push(format!(
+            "{} uses the key '{}', which cannot be read: {}",
+            owner, path, e
+        ));
+    }
+}
+
+fn check_action(action: &Action, names: &mut HashSet<String>, errors: &mut Vec<String>) {
+    if !names.insert(action.name().to_string()) {
+        errors.push(format!(
+            "action name '{}' is used more than once",
+            action.name()
+        ));
+    }
+    if let Err(reason) = remote_watch(action) {
+        errors.push(format!("action '{}' has an {}", action.name(), reason));
+    }
+    if let Action::Upload { source_folder, .. } = action {
+        if !Path::new(&expand_user_path(source_folder)).is_dir() {
+            errors.push(format!(
+                "action '{}' uploads '{}', which is not a local folder",
+                action.name(),
+                source_folder
+            ));
+        }
+    }
+}
+
+// Checks of a loaded config that need more than its content: local folders,
+// keys, patterns and the actions named by --skip
+pub fn check(config: &Config, skip: &HashSet<String>) -> Vec<String> {
+    let mut errors = Vec::new();
+
+    let mut names = HashSet::new();
+    for action in &config.actions {
+        check_action(action, &mut names, &mut errors);
+    }
+    if let Some(canary) = config.canary() {
+        let mut verify_names = HashSet::new();
+        for action in &canary.verify {
+            check_action(action, &mut verify_names, &mut errors);
+        }
+    }
+    let mut skipped: Vec<&String> = skip.iter().collect();
+    skipped.sort();
+    for name in skipped {
+        if !names.contains(name) {
+            errors.push(format!("--skip '{}' matches no action", name));
+        }
+    }
+
+    for (name, server) in &config.servers {
+        for hop in server.jump.iter().flatten().chain(std::iter::once(server)) {
+            let owner = format!("server '{}'", name);
+            if let Some(path) = &hop.ssh_key {
+                check_key_file(path, &owner, &mut errors);
+            }
+            for method in hop.auth.iter().flatten() {
+                if let AuthMethod::Key { path, .. } = method {
+                    check_key_file(path, &owner, &mut errors);
+                }
+            }
+        }
+    }
+    errors
+}